@@ -0,0 +1,26 @@
+use std::{env, fs, io, io::Write};
+
+use terminfo_lean::prelude::*;
+
+fn main() -> Result<(), Error> {
+    let term_name = env::var("TERM")?;
+    let terminfo_file = locate(term_name)?;
+    let terminfo_buffer = fs::read(&terminfo_file)?;
+    let terminfo = Terminfo::parse(&terminfo_buffer)?;
+    let Some(cap) = terminfo.strings.get("setaf") else {
+        println!("Your terminal has no color support");
+        return Ok(());
+    };
+
+    let mut context = ExpandContext::new();
+    for color in 1..8 {
+        let expanded = context.expand(cap, &[Parameter::from(color)])?;
+        io::stdout().write_all(&expanded)?;
+        println!("Color {color}");
+    }
+    if let Some(reset) = terminfo.strings.get("sgr0") {
+        io::stdout().write_all(reset)?;
+    }
+
+    Ok(())
+}
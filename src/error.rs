@@ -0,0 +1,37 @@
+// Copyright 2025 Pavel Roskin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Errors reported by functions combining several stages
+
+use std::{env, io};
+
+use crate::{expand, locate, parse, terminal};
+
+/// Errors reported by functions that locate, read, parse and expand terminfo
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The `TERM` environment variable is missing or not valid Unicode
+    #[error("Cannot get terminal name")]
+    Environment(#[from] env::VarError),
+    /// The terminfo database could not be found
+    #[error("Cannot find terminfo database")]
+    Locate(#[from] locate::Error),
+    /// The terminfo database could not be read
+    #[error("Cannot read terminfo database")]
+    Read(#[from] io::Error),
+    /// The terminfo database could not be parsed
+    #[error("Cannot parse terminfo database")]
+    Parse(#[from] parse::Error),
+    /// A capability could not be expanded
+    #[error("Cannot expand capability")]
+    Expand(#[from] expand::Error),
+    /// Output for the terminal could not be generated
+    #[error("Cannot generate terminal output")]
+    Terminal(#[from] terminal::Error),
+}
@@ -10,27 +10,84 @@
 
 //! Expansion of capability strings with parameters
 
-use std::{array::from_fn, iter::repeat_n};
+use std::{
+    array::from_fn,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    fmt,
+    hash::{Hash, Hasher},
+    io::{self, Write},
+    iter::repeat_n,
+    ops::Range,
+    str::FromStr,
+};
 
+/// Default limit for the size of the output of one expansion
+pub const DEFAULT_MAX_OUTPUT: usize = 64 * 1024;
+
+/// State of the capability compiler
 #[derive(Clone, Copy, PartialEq)]
 enum States {
     Nothing,
-    Delay,
+    Dollar,
+    Delay(Delay, DelayState),
     Percent,
     SetVar,
     GetVar,
     PushParam,
     CharConstant,
+    CharEscape,
     CharClose,
     IntConstant(i32),
     FormatPattern(Flags, FormatState),
-    SeekIfElse(usize),
-    SeekIfElsePercent(usize),
-    SeekIfEnd(usize),
-    SeekIfEndPercent(usize),
 }
 
-#[derive(Copy, PartialEq, Clone)]
+#[derive(Copy, PartialEq, Clone, Debug)]
+enum DelayState {
+    /// After `$<`
+    Open,
+    /// In the integer part
+    Integer,
+    /// After the decimal point
+    Point,
+    /// After the first digit after the decimal point
+    Decimals,
+    /// After `*` or `/`
+    Suffix,
+}
+
+/// Delay specified by `$<...>` in a capability
+#[derive(Copy, PartialEq, Eq, Clone, Default, Debug)]
+pub struct Delay {
+    /// Delay in tenths of a millisecond
+    tenths: u32,
+    /// The delay is per affected line (`*`)
+    proportional: bool,
+    /// The delay is mandatory even with XON/XOFF flow control (`/`)
+    mandatory: bool,
+}
+
+impl Delay {
+    /// Return the delay in tenths of a millisecond
+    #[must_use]
+    pub const fn tenths(&self) -> u32 {
+        self.tenths
+    }
+
+    /// Check whether the delay is per affected line (`*`)
+    #[must_use]
+    pub const fn is_proportional(&self) -> bool {
+        self.proportional
+    }
+
+    /// Check whether the delay is mandatory even with flow control (`/`)
+    #[must_use]
+    pub const fn is_mandatory(&self) -> bool {
+        self.mandatory
+    }
+}
+
+#[derive(Copy, PartialEq, Clone, Debug)]
 enum FormatState {
     Flags,
     Width,
@@ -38,38 +95,175 @@ enum FormatState {
 }
 
 /// Parameter that can be used for capability expansion
+///
+/// String parameters can be owned (`String`) or borrowed (`Str`). Conversions
+/// from slices and string references produce borrowed parameters to avoid
+/// copying, which ties the lifetime of the parameter to the borrowed data.
+///
+/// Owned and borrowed strings with the same bytes compare equal and have the
+/// same hash.
 #[derive(Clone)]
-pub enum Parameter {
+pub enum Parameter<'a> {
     Number(i32),
     String(Vec<u8>),
+    Str(&'a [u8]),
+}
+
+impl Parameter<'_> {
+    /// Return the bytes of a string parameter, `None` for a number
+    fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Number(_) => None,
+            Self::String(s) => Some(s),
+            Self::Str(s) => Some(s),
+        }
+    }
+
+    /// Return a parameter that borrows the string instead of owning it
+    const fn borrowed(&self) -> Parameter<'_> {
+        match self {
+            Self::Number(n) => Parameter::Number(*n),
+            Self::String(s) => Parameter::Str(s.as_slice()),
+            Self::Str(s) => Parameter::Str(s),
+        }
+    }
+
+    /// Convert the parameter to one that doesn't borrow any data
+    #[must_use]
+    pub fn into_owned(self) -> Parameter<'static> {
+        match self {
+            Self::Number(n) => Parameter::Number(n),
+            Self::String(s) => Parameter::String(s),
+            Self::Str(s) => Parameter::String(s.to_vec()),
+        }
+    }
+}
+
+impl PartialEq for Parameter<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Number(x), Self::Number(y)) => x == y,
+            _ => self.as_bytes().is_some() && self.as_bytes() == other.as_bytes(),
+        }
+    }
 }
 
-impl From<i32> for Parameter {
+impl Eq for Parameter<'_> {}
+
+impl Hash for Parameter<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Owned and borrowed strings must have the same hash
+        match self {
+            Self::Number(n) => n.hash(state),
+            Self::String(s) => s.as_slice().hash(state),
+            Self::Str(s) => s.hash(state),
+        }
+    }
+}
+
+impl fmt::Debug for Parameter<'_> {
+    /// Show strings that are valid UTF-8 as strings, others as byte lists
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, bytes) = match self {
+            Self::Number(n) => return f.debug_tuple("Number").field(n).finish(),
+            Self::String(s) => ("String", s.as_slice()),
+            Self::Str(s) => ("Str", *s),
+        };
+        match str::from_utf8(bytes) {
+            Ok(s) => f.debug_tuple(name).field(&s).finish(),
+            Err(_) => f.debug_tuple(name).field(&bytes).finish(),
+        }
+    }
+}
+
+impl fmt::Display for Parameter<'_> {
+    /// Show numbers in decimal, strings with invalid UTF-8 replaced
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{n}"),
+            Self::String(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+            Self::Str(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+        }
+    }
+}
+
+impl From<i32> for Parameter<'_> {
     fn from(value: i32) -> Self {
         Self::Number(value)
     }
 }
 
-impl From<&[u8]> for Parameter {
-    fn from(value: &[u8]) -> Self {
-        Self::String(value.to_vec())
+impl From<u8> for Parameter<'_> {
+    fn from(value: u8) -> Self {
+        Self::Number(i32::from(value))
+    }
+}
+
+impl From<u16> for Parameter<'_> {
+    fn from(value: u16) -> Self {
+        Self::Number(i32::from(value))
+    }
+}
+
+impl From<usize> for Parameter<'_> {
+    /// Values above `i32::MAX` are saturated to `i32::MAX`
+    fn from(value: usize) -> Self {
+        Self::Number(i32::try_from(value).unwrap_or(i32::MAX))
+    }
+}
+
+impl From<bool> for Parameter<'_> {
+    /// `true` is converted to 1, `false` to 0
+    fn from(value: bool) -> Self {
+        Self::Number(i32::from(value))
+    }
+}
+
+impl From<Vec<u8>> for Parameter<'_> {
+    fn from(value: Vec<u8>) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<String> for Parameter<'_> {
+    fn from(value: String) -> Self {
+        Self::String(value.into_bytes())
+    }
+}
+
+impl FromStr for Parameter<'_> {
+    type Err = Infallible;
+
+    /// Parse a number if possible, use the string as is otherwise
+    ///
+    /// This is how `tput` treats its arguments. Use `Parameter::from()` to
+    /// always get a string.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.parse()
+            .map_or_else(|_| Self::String(s.as_bytes().to_vec()), Self::Number))
+    }
+}
+
+impl<'a> From<&'a [u8]> for Parameter<'a> {
+    fn from(value: &'a [u8]) -> Self {
+        Self::Str(value)
     }
 }
 
-impl<const N: usize> From<&[u8; N]> for Parameter {
-    fn from(value: &[u8; N]) -> Self {
-        Self::String(value.to_vec())
+impl<'a, const N: usize> From<&'a [u8; N]> for Parameter<'a> {
+    fn from(value: &'a [u8; N]) -> Self {
+        Self::Str(value)
     }
 }
 
-impl From<&str> for Parameter {
-    fn from(value: &str) -> Self {
-        Self::String(value.as_bytes().to_vec())
+impl<'a> From<&'a str> for Parameter<'a> {
+    fn from(value: &'a str) -> Self {
+        Self::Str(value.as_bytes())
     }
 }
 
 /// Errors reported when expanding a string
-#[derive(thiserror::Error, Debug, PartialEq)]
+#[derive(thiserror::Error, Clone, Debug, PartialEq)]
 #[non_exhaustive]
 pub enum Error {
     /// The operator does not have required data on stack
@@ -105,15 +299,61 @@ pub enum Error {
     /// Argument type is incompatible with the format
     #[error("Unexpected type for format")]
     FormatTypeMismatch,
+    /// Result of an arithmetic operator doesn't fit into 32 bits
+    #[error("Arithmetic overflow in operator {0}")]
+    ArithmeticOverflow(char),
+    /// Output could not be written
+    #[error("Output error: {0}")]
+    IO(io::ErrorKind),
+    /// Capability ends with `%p` without a parameter index
+    #[error("Missing parameter index")]
+    MissingParameterIndex,
+    /// Delay specification `$<...>` is malformed or unterminated
+    #[error("Malformed delay")]
+    MalformedDelay,
+    /// Output exceeds the limit set by `set_max_output()`
+    #[error("Output too large")]
+    OutputTooLarge,
+    /// Capability exceeds the limit set by `set_max_capability_len()`
+    #[error("Capability too long")]
+    CapabilityTooLong,
+    /// `%;` without `%?` or `%?` without `%;`
+    #[error("Unbalanced conditional")]
+    UnbalancedConditional,
+    /// `%?`, `%t` or `%e` not terminated by `%;` in strict mode
+    #[error("Unterminated conditional")]
+    UnterminatedConditional,
+    /// Error found by `validate()` at the given offset in the capability
+    #[error("{error} at offset {offset}")]
+    Invalid { offset: usize, error: Box<Self> },
 }
 
 /// Context for variable expansion
 ///
 /// To be compatible with ncurses, the `ExpandContext` instance should be the same
 /// for the same terminal.
+#[allow(clippy::struct_excessive_bools)]
 pub struct ExpandContext {
     /// Static variables A-Z
-    static_variables: [Parameter; 26],
+    static_variables: [Parameter<'static>; 26],
+    /// Output speed in bits per second, `None` disables padding
+    baud_rate: Option<u32>,
+    /// Character used for padding
+    pad_char: u8,
+    /// Number of lines affected by the operation
+    affected_lines: u32,
+    /// Whether XON/XOFF flow control is used
+    xon_xoff: bool,
+    /// Maximal size of the output of one expansion
+    max_output: Option<usize>,
+    /// Maximal length of the capability to expand
+    max_capability_len: Option<usize>,
+    /// Whether parameters are converted to the type expected by the operator
+    lenient: bool,
+    /// Whether unterminated conditionals are rejected
+    strict_conditionals: bool,
+    /// Whether `%c` outputs 0200 for 0
+    nul_as_0x80: bool,
 }
 
 impl ExpandContext {
@@ -122,520 +362,2585 @@ impl ExpandContext {
     pub fn new() -> Self {
         Self {
             static_variables: from_fn(|_| Parameter::from(0)),
+            baud_rate: None,
+            pad_char: 0,
+            affected_lines: 1,
+            xon_xoff: false,
+            max_output: Some(DEFAULT_MAX_OUTPUT),
+            max_capability_len: None,
+            lenient: false,
+            strict_conditionals: false,
+            nul_as_0x80: true,
+        }
+    }
+
+    /// Return a builder for a context with non-default settings
+    #[must_use]
+    pub fn builder() -> ExpandContextBuilder {
+        ExpandContextBuilder::default()
+    }
+
+    /// Set the output speed for padding
+    ///
+    /// Delays specified by `$<...>` are ignored if the speed is `None`, which
+    /// is the default. Otherwise, pad characters are output for the duration
+    /// of the delay, assuming 9 bits per character like ncurses.
+    ///
+    /// The delay is in milliseconds with an optional tenth after a decimal
+    /// point. It's followed by optional suffixes: `*` multiplies the delay by
+    /// the number of affected lines, `/` makes the delay mandatory.
+    pub const fn set_baud_rate(&mut self, baud_rate: Option<u32>) {
+        self.baud_rate = baud_rate;
+    }
+
+    /// Set the character used for padding
+    ///
+    /// It should be taken from the `pad` capability if present. The default
+    /// is NUL.
+    pub const fn set_pad_char(&mut self, pad_char: u8) {
+        self.pad_char = pad_char;
+    }
+
+    /// Set the number of lines affected by the operation
+    ///
+    /// It applies to delays with the `*` suffix. The default is 1.
+    pub const fn set_affected_lines(&mut self, affected_lines: u32) {
+        self.affected_lines = affected_lines;
+    }
+
+    /// Set whether XON/XOFF flow control is used
+    ///
+    /// With flow control, only mandatory delays (with the `/` suffix) are
+    /// padded. The default is `false`.
+    pub const fn set_xon_xoff(&mut self, xon_xoff: bool) {
+        self.xon_xoff = xon_xoff;
+    }
+
+    /// Set the limit for the size of the output of one expansion
+    ///
+    /// If the output would exceed the limit, `Error::OutputTooLarge` is
+    /// returned. Formats with a large width or precision are checked before
+    /// formatting. `None` means no limit.
+    ///
+    /// The default is `DEFAULT_MAX_OUTPUT`, which is much more than any sane
+    /// capability produces, but protects against malicious terminfo entries.
+    pub const fn set_max_output(&mut self, limit: Option<usize>) {
+        self.max_output = limit;
+    }
+
+    /// Set the limit for the length of the capability to expand
+    ///
+    /// Longer capabilities are rejected with `Error::CapabilityTooLong` before
+    /// expansion. `None` means no limit, which is the default.
+    pub const fn set_max_capability_len(&mut self, limit: Option<usize>) {
+        self.max_capability_len = limit;
+    }
+
+    /// Set whether values of the wrong type are converted
+    ///
+    /// In lenient mode, a string used as a number is parsed as a decimal
+    /// number, or taken as 0 if that fails, and a number used as a string is
+    /// converted to its decimal form. Otherwise, `Error::TypeMismatch` or
+    /// `Error::FormatTypeMismatch` is returned, which is the default.
+    ///
+    /// For instance, `%l` applied to a number pushes the length of its decimal
+    /// form, including the minus sign.
+    pub const fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Set whether unterminated conditionals are rejected
+    ///
+    /// By default, a conditional without the final `%;` extends to the end of
+    /// the capability like in ncurses, and some terminfo entries rely on it.
+    /// In strict mode, `Error::UnterminatedConditional` is returned instead,
+    /// before any output is produced.
+    pub const fn set_strict_conditionals(&mut self, strict: bool) {
+        self.strict_conditionals = strict;
+    }
+
+    /// Set whether `%c` outputs byte 0200 for 0
+    ///
+    /// Terminfo strings cannot contain NUL, so ncurses outputs 0200 instead,
+    /// which is the default. Disable it to output NUL.
+    pub const fn set_nul_as_0x80(&mut self, nul_as_0x80: bool) {
+        self.nul_as_0x80 = nul_as_0x80;
+    }
+
+    /// Set a static variable
+    ///
+    /// Static variables are named `A` to `Z` and persist between expansions.
+    pub fn set_static(&mut self, name: char, value: Parameter<'_>) -> Result<(), Error> {
+        match name {
+            'A'..='Z' => {
+                self.static_variables[usize::from((name as u8) - b'A')] = value.into_owned();
+                Ok(())
+            }
+            _ => Err(Error::InvalidVariableName(name)),
+        }
+    }
+
+    /// Return the value of a static variable
+    pub fn get_static(&self, name: char) -> Result<&Parameter<'static>, Error> {
+        match name {
+            'A'..='Z' => Ok(&self.static_variables[usize::from((name as u8) - b'A')]),
+            _ => Err(Error::InvalidVariableName(name)),
         }
     }
 
+    /// Reset all static variables to 0
+    pub fn reset(&mut self) {
+        self.static_variables = from_fn(|_| Parameter::from(0));
+    }
+
     /// Expand a parameterized capability
     ///
+    /// Strings are byte strings, so `%l` pushes the length in bytes like in
+    /// ncurses, even if the string is valid UTF-8.
+    ///
+    /// # Conditionals
+    ///
+    /// Conditionals have the form `%? C %t T %e E %;`, where the else part is
+    /// optional and `%e C %t T` can be repeated for "else if". Like in ncurses,
+    /// `%?` only marks the nesting level and produces no operation, so `%t`
+    /// without `%?` pops the condition from the stack as usual. If the
+    /// condition is zero, `%t` skips to the operation after the matching `%e`,
+    /// or to the matching `%;`, or to the end of the capability if neither is
+    /// found. `%e` skips to the matching `%;` or to the end. Missing `%;` can
+    /// be rejected by `set_strict_conditionals()`.
+    ///
+    /// Like in C, any nonzero number is true for `%t`, `%A`, `%O` and `%!`,
+    /// including negative numbers. Comparisons and logical operators push 1
+    /// for true and 0 for false.
+    ///
+    /// `%i` increments the first two parameters only once per expansion, like
+    /// in ncurses, so repeating it has no effect. The parameters passed by the
+    /// caller are not modified, so every expansion starts with them again.
+    ///
     /// # Arguments
     /// * `cap`    - string to expand
     /// * `params` - vector of params for %p1 etc
-    pub fn expand(&mut self, cap: &[u8], params: &[Parameter]) -> Result<Vec<u8>, Error> {
-        let mut state = States::Nothing;
-
-        // expanded cap will only rarely be larger than the cap itself
-        let mut output = Vec::with_capacity(cap.len());
+    pub fn expand(&mut self, cap: &[u8], params: &[Parameter<'_>]) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::new();
+        self.expand_append(cap, params, &mut output)?;
+        Ok(output)
+    }
 
-        let mut stack = Vec::new();
+    /// Expand a parameterized capability into a writer
+    ///
+    /// Same as `expand()`, but the output is written to `out` without building
+    /// a temporary vector. The output is written in small pieces, so writers
+    /// that perform system calls should be buffered.
+    ///
+    /// Use `expand_append()` to expand into a vector.
+    ///
+    /// If an error occurs, the output produced before the error may have been
+    /// written already.
+    pub fn expand_into<W: Write>(
+        &mut self,
+        cap: &[u8],
+        params: &[Parameter<'_>],
+        out: &mut W,
+    ) -> Result<(), Error> {
+        self.expand_with_trace(cap, params, out)?;
+        Ok(())
+    }
 
-        // Dynamic variables a-z
-        let mut dynamic_variables: [Parameter; 26] = from_fn(|_| Parameter::from(0));
+    /// Expand a parameterized capability, appending to a vector
+    ///
+    /// Same as `expand()`, but the output is appended to `out` without
+    /// clearing it, so that many capabilities can be expanded into one buffer
+    /// without allocating a vector for each of them.
+    ///
+    /// If an error occurs, the output produced before the error may have been
+    /// appended already.
+    pub fn expand_append(
+        &mut self,
+        cap: &[u8],
+        params: &[Parameter<'_>],
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        // expanded cap will only rarely be larger than the cap itself
+        out.reserve(cap.len());
+        self.expand_into(cap, params, out)
+    }
 
-        // Copy parameters into a local vector for mutability
-        let mut mparams = params.to_vec();
+    /// Expand a parameterized capability with sparse parameters
+    ///
+    /// Same as `expand()`, but the parameters are looked up by their index,
+    /// which starts with 1 like in `%p1`. Parameters missing from the map are
+    /// set to 0, indices above 9 are ignored.
+    pub fn expand_sparse(
+        &mut self,
+        cap: &[u8],
+        params: &HashMap<u8, Parameter<'_>>,
+    ) -> Result<Vec<u8>, Error> {
+        let params: [Parameter<'_>; 9] = from_fn(|index| {
+            params
+                .get(&(index as u8 + 1))
+                .map_or_else(|| Parameter::from(0), Parameter::clone)
+        });
+        self.expand(cap, &params)
+    }
 
-        // The increment should only be done once
-        let mut incremented = false;
+    /// Expand a parameterized capability with parameters from an iterator
+    ///
+    /// Same as `expand()`, but the parameters are taken from `params`. Only
+    /// the first 9 parameters are used, as only they can be referenced.
+    pub fn expand_iter<'p>(
+        &mut self,
+        cap: &[u8],
+        params: impl IntoIterator<Item = Parameter<'p>>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut params = params.into_iter();
+        let params: [Parameter<'p>; 9] =
+            from_fn(|_| params.next().unwrap_or_else(|| Parameter::from(0)));
+        self.expand(cap, &params)
+    }
 
-        // Make sure there are at least 9 parameters
-        while mparams.len() < 9 {
-            mparams.push(Parameter::from(0));
+    /// Expand a parameterized capability lazily
+    ///
+    /// Same as `expand()`, but the output is produced by an iterator as it's
+    /// consumed, so that large outputs don't need to be buffered. An error is
+    /// returned as the last item of the iterator.
+    pub fn expand_stream<'a, 'p>(
+        &'a mut self,
+        cap: &[u8],
+        params: &'a [Parameter<'p>],
+    ) -> impl Iterator<Item = Result<u8, Error>> + use<'a, 'p> {
+        let (prog, error) = match self
+            .check_capability_len(cap)
+            .and_then(|()| CompiledCap::new(cap))
+            .and_then(|prog| self.check_conditionals(&prog).map(|()| prog))
+        {
+            Ok(prog) => (prog, None),
+            Err(error) => (CompiledCap::default(), Some(error)),
+        };
+        ExpandStream {
+            remaining: self.max_output,
+            context: self,
+            prog,
+            machine: Machine::new(LazyParams::new(slice_provider(params))),
+            buffer: vec![],
+            position: 0,
+            error,
+            finished: false,
         }
+    }
 
-        for &c in cap {
-            let cur = c as char;
-            let mut old_state = state;
-            match state {
-                States::Nothing => {
-                    if cur == '%' {
-                        state = States::Percent;
-                    } else if cur == '$' {
-                        state = States::Delay;
-                    } else {
-                        output.push(c);
-                    }
-                }
-                States::Delay => {
-                    old_state = States::Nothing;
-                    if cur == '>' {
-                        state = States::Nothing;
-                    }
-                }
-                States::Percent => {
-                    match cur {
-                        '%' => {
-                            output.push(c);
-                            state = States::Nothing;
-                        }
-                        'c' => {
-                            match stack.pop() {
-                                // if c is 0, use 0200 (128) for ncurses compatibility
-                                Some(Parameter::Number(0)) => output.push(128u8),
-                                // Don't check bounds. ncurses just casts and truncates.
-                                Some(Parameter::Number(c)) => output.push(c as u8),
-                                Some(_) => return Err(Error::TypeMismatch(cur)),
-                                None => return Err(Error::StackUnderflow(cur)),
-                            }
-                        }
-                        'p' => state = States::PushParam,
-                        'P' => state = States::SetVar,
-                        'g' => state = States::GetVar,
-                        '\'' => state = States::CharConstant,
-                        '{' => state = States::IntConstant(0),
-                        'l' => match stack.pop() {
-                            Some(Parameter::String(s)) => {
-                                stack.push(Parameter::from(s.len() as i32));
-                            }
-                            Some(_) => return Err(Error::TypeMismatch(cur)),
-                            None => return Err(Error::StackUnderflow(cur)),
-                        },
-                        '+' | '-' | '*' | '/' | '|' | '&' | '^' | 'm' => {
-                            match (stack.pop(), stack.pop()) {
-                                (Some(Parameter::Number(y)), Some(Parameter::Number(x))) => {
-                                    let result = match cur {
-                                        '+' => x + y,
-                                        '-' => x - y,
-                                        '*' => x * y,
-                                        '/' => x / y,
-                                        '|' => x | y,
-                                        '&' => x & y,
-                                        '^' => x ^ y,
-                                        'm' => x % y,
-                                        _ => unreachable!("logic error"),
-                                    };
-                                    stack.push(Parameter::from(result));
-                                }
-                                (Some(_), Some(_)) => return Err(Error::TypeMismatch(cur)),
-                                _ => return Err(Error::StackUnderflow(cur)),
-                            }
-                        }
-                        '=' | '>' | '<' | 'A' | 'O' => match (stack.pop(), stack.pop()) {
-                            (Some(Parameter::Number(y)), Some(Parameter::Number(x))) => {
-                                let result = match cur {
-                                    '=' => x == y,
-                                    '<' => x < y,
-                                    '>' => x > y,
-                                    'A' => x > 0 && y > 0,
-                                    'O' => x > 0 || y > 0,
-                                    _ => unreachable!("logic error"),
-                                };
-                                stack.push(Parameter::from(i32::from(result)));
-                            }
-                            (Some(_), Some(_)) => return Err(Error::TypeMismatch(cur)),
-                            _ => return Err(Error::StackUnderflow(cur)),
-                        },
-                        '!' | '~' => match stack.pop() {
-                            Some(Parameter::Number(x)) => {
-                                stack.push(Parameter::Number(match cur {
-                                    '!' if x > 0 => 0,
-                                    '!' => 1,
-                                    '~' => !x,
-                                    _ => unreachable!("logic error"),
-                                }));
-                            }
-                            Some(_) => return Err(Error::TypeMismatch(cur)),
-                            None => return Err(Error::StackUnderflow(cur)),
-                        },
-                        'i' => match (&mparams[0], &mparams[1]) {
-                            (&Parameter::Number(x), &Parameter::Number(y)) => {
-                                if !incremented {
-                                    mparams[0] = Parameter::from(x + 1);
-                                    mparams[1] = Parameter::from(y + 1);
-                                    incremented = true;
-                                }
-                            }
-                            (_, _) => return Err(Error::TypeMismatch(cur)),
-                        },
-
-                        // printf-style support for %doxXs
-                        'd' | 'o' | 'x' | 'X' | 's' => {
-                            if let Some(arg) = stack.pop() {
-                                let flags = Flags::default();
-                                let result = format(arg, cur, flags)?;
-                                output.extend(result);
-                            } else {
-                                return Err(Error::StackUnderflow(cur));
-                            }
-                        }
-                        ':' | '#' | ' ' | '.' | '0'..='9' => {
-                            let mut flags = Flags::default();
-                            let mut fstate = FormatState::Flags;
-                            match cur {
-                                ':' => (),
-                                '#' => flags.alternate = true,
-                                ' ' => flags.sign = SignFlags::Space,
-                                '.' => fstate = FormatState::Precision,
-                                '0'..='9' => {
-                                    flags.width = cur as u16 - '0' as u16;
-                                    fstate = FormatState::Width;
-                                }
-                                _ => unreachable!("logic error"),
-                            }
-                            state = States::FormatPattern(flags, fstate);
-                        }
-
-                        // conditionals
-                        '?' | ';' => (),
-                        't' => match stack.pop() {
-                            Some(Parameter::Number(0)) => state = States::SeekIfElse(0),
-                            Some(Parameter::Number(_)) => (),
-                            Some(_) => return Err(Error::TypeMismatch(cur)),
-                            None => return Err(Error::StackUnderflow(cur)),
-                        },
-                        'e' => state = States::SeekIfEnd(0),
-                        c => return Err(Error::UnrecognizedFormatOption(c)),
-                    }
-                }
-                States::PushParam => {
-                    // params are 1-indexed
-                    let index = match cur {
-                        '1'..='9' => cur as usize - '1' as usize,
-                        _ => return Err(Error::InvalidParameterIndex(cur)),
-                    };
-                    stack.push(mparams[index].clone());
-                }
-                States::SetVar => {
-                    let Some(arg) = stack.pop() else {
-                        return Err(Error::StackUnderflow('P'));
+    /// Expand a capability written in termcap syntax
+    ///
+    /// This is for strings like `cm` from termcap databases, which use a
+    /// different language than terminfo. The parameters are consumed in order
+    /// by the operators that output them, like in `tgoto()`:
+    ///
+    /// * `%d`, `%2`, `%3` - output in decimal, padded with zeros to 2 or 3 digits
+    /// * `%.` - output as a character
+    /// * `%+x` - add the character `x` and output as a character
+    /// * `%>xy` - if greater than the character `x`, add the character `y`
+    /// * `%r` - swap the first two parameters
+    /// * `%i` - increment the first two parameters
+    /// * `%n` - XOR the first two parameters with 0140
+    /// * `%B`, `%D` - convert to BCD or reverse coding like in terminfo
+    /// * `%%` - output `%`
+    ///
+    /// Missing parameters are taken as 0. Delays and the terminfo operators
+    /// are not supported.
+    pub fn expand_termcap(&self, cap: &[u8], params: &[i32]) -> Result<Vec<u8>, Error> {
+        self.check_capability_len(cap)?;
+        let mut params = params.to_vec();
+        params.resize(params.len().max(2), 0);
+        let mut output = Vec::with_capacity(cap.len());
+        let mut out = Output {
+            out: &mut output,
+            remaining: self.max_output,
+        };
+        let mut index = 0;
+        let mut bytes = cap.iter().copied();
+        while let Some(byte) = bytes.next() {
+            if byte != b'%' {
+                out.write(&[byte])?;
+                continue;
+            }
+            let Some(op) = bytes.next() else {
+                return Err(Error::UnrecognizedFormatOption('%'));
+            };
+            if index >= params.len() {
+                params.push(0);
+            }
+            let value = params[index];
+            match op {
+                b'%' => out.write(b"%")?,
+                b'd' => out.write(value.to_string().as_bytes())?,
+                b'2' => out.write(format!("{value:02}").as_bytes())?,
+                b'3' => out.write(format!("{value:03}").as_bytes())?,
+                b'.' => out.write(&[value as u8])?,
+                b'+' => {
+                    let Some(offset) = bytes.next() else {
+                        return Err(Error::MalformedCharacterConstant);
                     };
-                    match cur {
-                        'A'..='Z' => self.static_variables[usize::from((cur as u8) - b'A')] = arg,
-                        'a'..='z' => dynamic_variables[usize::from((cur as u8) - b'a')] = arg,
-                        _ => return Err(Error::InvalidVariableName(cur)),
-                    }
-                }
-                States::GetVar => {
-                    let value = match cur {
-                        'A'..='Z' => &self.static_variables[usize::from((cur as u8) - b'A')],
-                        'a'..='z' => &dynamic_variables[usize::from((cur as u8) - b'a')],
-                        _ => return Err(Error::InvalidVariableName(cur)),
+                    let Some(value) = value.checked_add(i32::from(offset)) else {
+                        return Err(Error::ArithmeticOverflow('+'));
                     };
-                    stack.push(value.clone());
-                }
-                States::CharConstant => {
-                    stack.push(Parameter::from(i32::from(c)));
-                    state = States::CharClose;
+                    out.write(&[value as u8])?;
                 }
-                States::CharClose => {
-                    if cur != '\'' {
+                b'>' => {
+                    let (Some(limit), Some(offset)) = (bytes.next(), bytes.next()) else {
                         return Err(Error::MalformedCharacterConstant);
+                    };
+                    if value > i32::from(limit) {
+                        let Some(value) = value.checked_add(i32::from(offset)) else {
+                            return Err(Error::ArithmeticOverflow('>'));
+                        };
+                        params[index] = value;
                     }
                 }
-                States::IntConstant(i) => {
-                    if cur == '}' {
-                        stack.push(Parameter::from(i));
-                        state = States::Nothing;
-                    } else if let Some(digit) = cur.to_digit(10) {
-                        match i
-                            .checked_mul(10)
-                            .and_then(|i_ten| i_ten.checked_add(digit as i32))
-                        {
-                            Some(i) => {
-                                state = States::IntConstant(i);
-                                old_state = States::Nothing;
-                            }
-                            None => return Err(Error::IntegerConstantOverflow),
-                        }
-                    } else {
-                        return Err(Error::MalformedIntegerConstant);
-                    }
-                }
-                States::FormatPattern(ref mut flags, ref mut fstate) => {
-                    old_state = States::Nothing;
-                    match (*fstate, cur) {
-                        (_, 'd' | 'o' | 'x' | 'X' | 's') => {
-                            if let Some(arg) = stack.pop() {
-                                let res = format(arg, cur, *flags)?;
-                                output.extend(res);
-                                // will cause state to go to States::Nothing
-                                old_state = States::FormatPattern(*flags, *fstate);
-                            } else {
-                                return Err(Error::StackUnderflow(cur));
-                            }
-                        }
-                        (FormatState::Flags, '#') => {
-                            flags.alternate = true;
-                        }
-                        (FormatState::Flags, '-') => {
-                            flags.left = true;
-                        }
-                        (FormatState::Flags, '+') => {
-                            flags.sign = SignFlags::Plus;
-                        }
-                        (FormatState::Flags, ' ') => {
-                            flags.sign = SignFlags::Space;
-                        }
-                        (FormatState::Flags, '0'..='9') => {
-                            flags.width = cur as u16 - '0' as u16;
-                            *fstate = FormatState::Width;
-                        }
-                        (FormatState::Width, '0'..='9') => {
-                            flags.width = match flags
-                                .width
-                                .checked_mul(10)
-                                .and_then(|w| w.checked_add(cur as u16 - '0' as u16))
-                            {
-                                Some(width) => width,
-                                None => return Err(Error::FormatWidthOverflow),
-                            }
-                        }
-                        (FormatState::Width | FormatState::Flags, '.') => {
-                            *fstate = FormatState::Precision;
-                        }
-                        (FormatState::Precision, '0'..='9') => {
-                            flags.precision = match flags
-                                .precision
-                                .unwrap_or(0)
-                                .checked_mul(10)
-                                .and_then(|w| w.checked_add(cur as u16 - '0' as u16))
-                            {
-                                Some(precision) => Some(precision),
-                                None => return Err(Error::FormatPrecisionOverflow),
-                            }
-                        }
-                        _ => return Err(Error::UnrecognizedFormatOption(cur)),
-                    }
-                }
-                States::SeekIfElse(level) => {
-                    if cur == '%' {
-                        state = States::SeekIfElsePercent(level);
-                    }
-                    old_state = States::Nothing;
-                }
-                States::SeekIfElsePercent(level) => {
-                    if cur == ';' {
-                        if level == 0 {
-                            state = States::Nothing;
-                        } else {
-                            state = States::SeekIfElse(level - 1);
-                        }
-                    } else if cur == 'e' && level == 0 {
-                        state = States::Nothing;
-                    } else if cur == '?' {
-                        state = States::SeekIfElse(level + 1);
-                    } else {
-                        state = States::SeekIfElse(level);
-                    }
+                b'r' => params.swap(0, 1),
+                b'i' => {
+                    let (Some(x), Some(y)) = (params[0].checked_add(1), params[1].checked_add(1))
+                    else {
+                        return Err(Error::ArithmeticOverflow('i'));
+                    };
+                    params[0] = x;
+                    params[1] = y;
                 }
-                States::SeekIfEnd(level) => {
-                    if cur == '%' {
-                        state = States::SeekIfEndPercent(level);
-                    }
-                    old_state = States::Nothing;
+                b'n' => {
+                    params[0] ^= 0o140;
+                    params[1] ^= 0o140;
                 }
-                States::SeekIfEndPercent(level) => {
-                    if cur == ';' {
-                        if level == 0 {
-                            state = States::Nothing;
-                        } else {
-                            state = States::SeekIfEnd(level - 1);
-                        }
-                    } else if cur == '?' {
-                        state = States::SeekIfEnd(level + 1);
-                    } else {
-                        state = States::SeekIfEnd(level);
-                    }
+                b'B' => {
+                    let Some(bcd) = (value / 10)
+                        .checked_mul(16)
+                        .and_then(|tens| tens.checked_add(value % 10))
+                    else {
+                        return Err(Error::ArithmeticOverflow('B'));
+                    };
+                    params[index] = bcd;
                 }
+                b'D' => params[index] = value - 2 * (value % 16),
+                _ => return Err(Error::UnrecognizedFormatOption(char::from(op))),
             }
-            if state == old_state {
-                state = States::Nothing;
+            if matches!(op, b'd' | b'2' | b'3' | b'.' | b'+') {
+                index += 1;
             }
         }
         Ok(output)
     }
-}
 
-#[derive(Copy, PartialEq, Clone, Default)]
-enum SignFlags {
-    #[default]
-    Empty,
-    Space,
-    Plus,
+    /// Expand a parameterized capability and record variable usage
+    ///
+    /// Same as `expand()`, but also returns the variables that were read and
+    /// written during the expansion.
+    pub fn expand_traced(
+        &mut self,
+        cap: &[u8],
+        params: &[Parameter<'_>],
+    ) -> Result<(Vec<u8>, VarTrace), Error> {
+        let mut output = Vec::with_capacity(cap.len());
+        let trace = self.expand_with_trace(cap, params, &mut output)?;
+        Ok((output, trace))
+    }
+
+    /// Compile a capability for repeated expansion
+    ///
+    /// Same as `CompiledCap::compile()`.
+    pub fn compile(cap: &[u8]) -> Result<CompiledCap, Error> {
+        CompiledCap::compile(cap)
+    }
+
+    /// Check a capability for errors without expanding it
+    ///
+    /// In addition to the errors reported by `CompiledCap::compile()`,
+    /// unbalanced conditionals, invalid variable names and stack underflows on
+    /// any path through the conditionals are detected. Parameters are assumed
+    /// to have the types expected by the operators.
+    ///
+    /// The first error is returned as `Error::Invalid` with the offset of the
+    /// sequence that caused it.
+    pub fn validate(cap: &[u8]) -> Result<(), Error> {
+        CompiledCap::validate(cap).map_err(|(offset, error)| Error::Invalid {
+            offset,
+            error: Box::new(error),
+        })
+    }
+
+    /// Expand a compiled capability
+    ///
+    /// Same as `CompiledCap::expand()`.
+    pub fn run(&mut self, prog: &CompiledCap, params: &[Parameter<'_>]) -> Result<Vec<u8>, Error> {
+        prog.expand(self, params)
+    }
+
+    /// Expand a parameterized capability with parameters computed on demand
+    ///
+    /// Same as `expand()`, but the parameters are obtained from `provider`,
+    /// which is called with the index starting with 1 like in `%p1`. It's only
+    /// called for parameters that are actually used, at most once for each
+    /// index, so that expensive parameters skipped by conditionals are not
+    /// computed.
+    pub fn expand_with<'p>(
+        &mut self,
+        cap: &[u8],
+        provider: impl FnMut(usize) -> Parameter<'p>,
+    ) -> Result<Vec<u8>, Error> {
+        self.check_capability_len(cap)?;
+        let prog = CompiledCap::new(cap)?;
+        let mut output = Vec::with_capacity(cap.len());
+        self.run_with_trace(&prog, LazyParams::new(provider), &mut output)?;
+        Ok(output)
+    }
+
+    const fn check_capability_len(&self, cap: &[u8]) -> Result<(), Error> {
+        match self.max_capability_len {
+            Some(limit) if cap.len() > limit => Err(Error::CapabilityTooLong),
+            _ => Ok(()),
+        }
+    }
+
+    const fn check_conditionals(&self, prog: &CompiledCap) -> Result<(), Error> {
+        if self.strict_conditionals && prog.unterminated {
+            Err(Error::UnterminatedConditional)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn expand_with_trace<W: Write>(
+        &mut self,
+        cap: &[u8],
+        params: &[Parameter<'_>],
+        out: &mut W,
+    ) -> Result<VarTrace, Error> {
+        self.check_capability_len(cap)?;
+        let prog = CompiledCap::new(cap)?;
+        self.run_with_trace(&prog, LazyParams::new(slice_provider(params)), out)
+    }
+
+    fn run_with_trace<'p, W: Write>(
+        &mut self,
+        prog: &CompiledCap,
+        params: LazyParams<'p, impl FnMut(usize) -> Parameter<'p>>,
+        out: &mut W,
+    ) -> Result<VarTrace, Error> {
+        self.check_conditionals(prog)?;
+        let mut out = Output {
+            out,
+            remaining: self.max_output,
+        };
+        let mut machine = Machine::new(params);
+        while machine.step(self, prog, &mut out)? {}
+        Ok(machine.trace)
+    }
+
+    /// Convert the value to a number or a string in lenient mode
+    fn coerce<'p>(&self, value: Option<Parameter<'p>>, numeric: bool) -> Option<Parameter<'p>> {
+        if !self.lenient {
+            return value;
+        }
+        let value = value?;
+        match (&value, value.as_bytes()) {
+            (Parameter::Number(n), _) if !numeric => {
+                Some(Parameter::String(n.to_string().into_bytes()))
+            }
+            (_, Some(s)) if numeric => {
+                let number = str::from_utf8(s)
+                    .ok()
+                    .and_then(|s| s.trim().parse().ok())
+                    .unwrap_or(0);
+                Some(Parameter::Number(number))
+            }
+            _ => Some(value),
+        }
+    }
+
+    /// Number of padding characters for the delay
+    fn pad_count(&self, delay: Delay) -> u64 {
+        let Some(baud_rate) = self.baud_rate else {
+            return 0;
+        };
+        if self.xon_xoff && !delay.mandatory {
+            return 0;
+        }
+        let lines = if delay.proportional {
+            u64::from(self.affected_lines)
+        } else {
+            1
+        };
+        u64::from(delay.tenths)
+            .saturating_mul(lines)
+            .saturating_mul(u64::from(baud_rate))
+            / 90_000
+    }
 }
 
-#[derive(Copy, PartialEq, Clone, Default)]
-struct Flags {
-    width: u16,
-    precision: Option<u16>,
-    alternate: bool,
-    left: bool,
-    sign: SignFlags,
+/// Parameters obtained from the provider on first use
+struct LazyParams<'p, F> {
+    values: [Option<Parameter<'p>>; 9],
+    provider: F,
 }
 
-fn format(val: Parameter, op: char, flags: Flags) -> Result<Vec<u8>, Error> {
-    let mut s = match val {
-        Parameter::Number(d) => {
-            match op {
-                'd' => match flags.precision {
-                    Some(precision) => {
-                        if d < 0 {
-                            format!("{d:0prec$}", prec = usize::from(precision + 1))
-                        } else {
-                            match flags.sign {
-                                SignFlags::Empty => {
-                                    format!("{d:0prec$}", prec = precision.into())
-                                }
-                                SignFlags::Space => {
-                                    format!(" {d:0prec$}", prec = precision.into())
-                                }
-                                SignFlags::Plus => {
-                                    format!("{d:+0prec$}", prec = usize::from(precision + 1))
-                                }
-                            }
-                        }
-                    }
-                    None => {
-                        if d < 0 {
-                            format!("{d}")
-                        } else {
-                            match flags.sign {
-                                SignFlags::Empty => {
-                                    format!("{d}")
-                                }
-                                SignFlags::Space => {
-                                    format!(" {d}")
-                                }
-                                SignFlags::Plus => {
-                                    format!("{d:+}")
-                                }
-                            }
-                        }
+impl<'p, F: FnMut(usize) -> Parameter<'p>> LazyParams<'p, F> {
+    fn new(provider: F) -> Self {
+        Self {
+            values: from_fn(|_| None),
+            provider,
+        }
+    }
+
+    /// Return the parameter with the zero-based index
+    fn get(&mut self, index: usize) -> &mut Parameter<'p> {
+        let provider = &mut self.provider;
+        self.values[index].get_or_insert_with(|| provider(index + 1))
+    }
+}
+
+/// Provide parameters from a slice, missing parameters are 0
+///
+/// Strings are borrowed from the slice, so that parameters are never copied.
+fn slice_provider<'s>(params: &'s [Parameter<'_>]) -> impl FnMut(usize) -> Parameter<'s> {
+    |index| {
+        params
+            .get(index - 1)
+            .map_or_else(|| Parameter::from(0), Parameter::borrowed)
+    }
+}
+
+/// State of an expansion that can be run one operation at a time
+struct Machine<'p, F> {
+    params: LazyParams<'p, F>,
+    stack: Vec<Parameter<'p>>,
+    /// Dynamic variables a-z
+    dynamic_variables: [Parameter<'p>; 26],
+    /// The increment should only be done once
+    incremented: bool,
+    /// Index of the next operation
+    pc: usize,
+    /// Padding characters still to be written
+    padding: u64,
+    trace: VarTrace,
+}
+
+impl<'p, F: FnMut(usize) -> Parameter<'p>> Machine<'p, F> {
+    fn new(params: LazyParams<'p, F>) -> Self {
+        Self {
+            params,
+            stack: Vec::new(),
+            dynamic_variables: from_fn(|_| Parameter::from(0)),
+            incremented: false,
+            pc: 0,
+            padding: 0,
+            trace: VarTrace::default(),
+        }
+    }
+
+    /// Execute the next operation, return `false` if there are none left
+    ///
+    /// Padding is written in chunks of at most 64 bytes per step.
+    fn step(
+        &mut self,
+        ctx: &mut ExpandContext,
+        prog: &CompiledCap,
+        out: &mut Output<'_, impl Write>,
+    ) -> Result<bool, Error> {
+        if self.padding > 0 {
+            let chunk = [ctx.pad_char; 64];
+            let size = self.padding.min(chunk.len() as u64);
+            out.write(&chunk[..size as usize])?;
+            self.padding -= size;
+            return Ok(true);
+        }
+        let Some(op) = prog.ops.get(self.pc) else {
+            return Ok(false);
+        };
+        self.pc += 1;
+        match *op {
+            Op::Literal(ref range) => out.write(&prog.literals[range.clone()])?,
+            Op::Char => {
+                match ctx.coerce(self.stack.pop(), true) {
+                    // if c is 0, use 0200 (128) for ncurses compatibility
+                    Some(Parameter::Number(0)) if ctx.nul_as_0x80 => out.write(&[128u8])?,
+                    // Don't check bounds. ncurses just casts and truncates.
+                    Some(Parameter::Number(c)) => out.write(&[c as u8])?,
+                    Some(_) => return Err(Error::TypeMismatch('c')),
+                    None => return Err(Error::StackUnderflow('c')),
+                }
+            }
+            Op::PushParam(index) => self.stack.push(self.params.get(index).clone()),
+            Op::SetVar(name) => {
+                // The stack is checked before the name for ncurses compatibility.
+                let Some(arg) = self.stack.pop() else {
+                    return Err(Error::StackUnderflow('P'));
+                };
+                match name {
+                    'A'..='Z' => {
+                        ctx.static_variables[usize::from((name as u8) - b'A')] = arg.into_owned();
                     }
+                    'a'..='z' => self.dynamic_variables[usize::from((name as u8) - b'a')] = arg,
+                    _ => return Err(Error::InvalidVariableName(name)),
+                }
+                self.trace.written |= VarTrace::mask(name);
+            }
+            Op::GetVar(name) => {
+                let value = match name {
+                    'A'..='Z' => &ctx.static_variables[usize::from((name as u8) - b'A')],
+                    _ => &self.dynamic_variables[usize::from((name as u8) - b'a')],
+                };
+                self.stack.push(value.clone());
+                self.trace.read |= VarTrace::mask(name);
+            }
+            Op::PushInt(value) => self.stack.push(Parameter::from(value)),
+            Op::Length => match ctx.coerce(self.stack.pop(), false) {
+                Some(arg) => match arg.as_bytes() {
+                    Some(s) => self.stack.push(Parameter::from(s.len() as i32)),
+                    None => return Err(Error::TypeMismatch('l')),
                 },
-                'o' => match flags.precision {
-                    Some(precision) => {
-                        if flags.alternate {
-                            // Leading octal zero counts against precision.
-                            format!("0{d:0prec$o}", prec = precision.saturating_sub(1).into())
-                        } else {
-                            format!("{d:0prec$o}", prec = precision.into())
-                        }
+                None => return Err(Error::StackUnderflow('l')),
+            },
+            Op::Binary(cur) => match (
+                ctx.coerce(self.stack.pop(), true),
+                ctx.coerce(self.stack.pop(), true),
+            ) {
+                (Some(Parameter::Number(y)), Some(Parameter::Number(x))) => {
+                    let result = match cur {
+                        '+' => x.checked_add(y),
+                        '-' => x.checked_sub(y),
+                        '*' => x.checked_mul(y),
+                        // ncurses yields 0 when dividing by zero
+                        '/' | 'm' if y == 0 => Some(0),
+                        '/' => x.checked_div(y),
+                        'm' => x.checked_rem(y),
+                        '|' => Some(x | y),
+                        '&' => Some(x & y),
+                        '^' => Some(x ^ y),
+                        '=' => Some(i32::from(x == y)),
+                        '<' => Some(i32::from(x < y)),
+                        '>' => Some(i32::from(x > y)),
+                        'A' => Some(i32::from(x != 0 && y != 0)),
+                        'O' => Some(i32::from(x != 0 || y != 0)),
+                        _ => unreachable!("logic error"),
+                    };
+                    let Some(result) = result else {
+                        return Err(Error::ArithmeticOverflow(cur));
+                    };
+                    self.stack.push(Parameter::from(result));
+                }
+                (Some(_), Some(_)) => return Err(Error::TypeMismatch(cur)),
+                _ => return Err(Error::StackUnderflow(cur)),
+            },
+            Op::Unary(cur) => match ctx.coerce(self.stack.pop(), true) {
+                Some(Parameter::Number(x)) => {
+                    let result = match cur {
+                        '!' => Some(i32::from(x == 0)),
+                        '~' => Some(!x),
+                        // binary-coded decimal, two digits per byte
+                        'B' => (x / 10)
+                            .checked_mul(16)
+                            .and_then(|tens| tens.checked_add(x % 10)),
+                        // reverse coding of the Delta Data terminals
+                        'D' => Some(x - 2 * (x % 16)),
+                        _ => unreachable!("logic error"),
+                    };
+                    let Some(result) = result else {
+                        return Err(Error::ArithmeticOverflow(cur));
+                    };
+                    self.stack.push(Parameter::Number(result));
+                }
+                Some(_) => return Err(Error::TypeMismatch(cur)),
+                None => return Err(Error::StackUnderflow(cur)),
+            },
+            Op::Increment => match (
+                ctx.coerce(Some(self.params.get(0).clone()), true),
+                ctx.coerce(Some(self.params.get(1).clone()), true),
+            ) {
+                (Some(Parameter::Number(x)), Some(Parameter::Number(y))) => {
+                    if !self.incremented {
+                        let (Some(x), Some(y)) = (x.checked_add(1), y.checked_add(1)) else {
+                            return Err(Error::ArithmeticOverflow('i'));
+                        };
+                        *self.params.get(0) = Parameter::from(x);
+                        *self.params.get(1) = Parameter::from(y);
+                        self.incremented = true;
                     }
-                    None => {
-                        if flags.alternate {
-                            format!("0{d:o}")
-                        } else {
-                            format!("{d:o}")
-                        }
+                }
+                (_, _) => return Err(Error::TypeMismatch('i')),
+            },
+            Op::XorParams => match (
+                ctx.coerce(Some(self.params.get(0).clone()), true),
+                ctx.coerce(Some(self.params.get(1).clone()), true),
+            ) {
+                (Some(Parameter::Number(x)), Some(Parameter::Number(y))) => {
+                    *self.params.get(0) = Parameter::from(x ^ 0o140);
+                    *self.params.get(1) = Parameter::from(y ^ 0o140);
+                }
+                (_, _) => return Err(Error::TypeMismatch('n')),
+            },
+            Op::Format(cur, flags) => {
+                if let Some(arg) = ctx.coerce(self.stack.pop(), cur != 's') {
+                    out.check(usize::from(flags.width.max(flags.precision.unwrap_or(0))))?;
+                    let result = format(&arg, cur, flags)?;
+                    out.write(&result)?;
+                } else {
+                    return Err(Error::StackUnderflow(cur));
+                }
+            }
+            Op::Then(target) => match ctx.coerce(self.stack.pop(), true) {
+                Some(Parameter::Number(0)) => self.pc = target,
+                Some(Parameter::Number(_)) => (),
+                Some(_) => return Err(Error::TypeMismatch('t')),
+                None => return Err(Error::StackUnderflow('t')),
+            },
+            Op::Else(target) => self.pc = target,
+            Op::Pad(delay) => self.padding = ctx.pad_count(delay),
+        }
+        Ok(true)
+    }
+}
+
+/// Iterator over the output of an expansion
+struct ExpandStream<'c, 'p, F> {
+    context: &'c mut ExpandContext,
+    prog: CompiledCap,
+    machine: Machine<'p, F>,
+    /// Number of bytes that can still be produced, `None` if unlimited
+    remaining: Option<usize>,
+    /// Output of the last step
+    buffer: Vec<u8>,
+    /// Position of the next byte in `buffer`
+    position: usize,
+    /// Error to be returned after the buffered output, if any
+    error: Option<Error>,
+    /// No more output is produced after an error
+    finished: bool,
+}
+
+impl<'p, F: FnMut(usize) -> Parameter<'p>> Iterator for ExpandStream<'_, 'p, F> {
+    type Item = Result<u8, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(&byte) = self.buffer.get(self.position) {
+                self.position += 1;
+                return Some(Ok(byte));
+            }
+            if let Some(error) = self.error.take() {
+                self.finished = true;
+                return Some(Err(error));
+            }
+            if self.finished {
+                return None;
+            }
+            self.buffer.clear();
+            self.position = 0;
+            let mut out = Output {
+                out: &mut self.buffer,
+                remaining: self.remaining,
+            };
+            match self.machine.step(self.context, &self.prog, &mut out) {
+                Ok(true) => self.remaining = out.remaining,
+                Ok(false) => return None,
+                Err(error) => self.error = Some(error),
+            }
+        }
+    }
+}
+
+/// Decoded operation of a compiled capability
+#[derive(Clone, Debug, PartialEq)]
+enum Op {
+    /// Output literal bytes
+    Literal(Range<usize>),
+    /// Output the number on top of the stack as a character
+    Char,
+    /// Push the parameter with the zero-based index
+    PushParam(usize),
+    /// Pop a value into the variable
+    SetVar(char),
+    /// Push the value of the variable
+    GetVar(char),
+    /// Push an integer or character constant
+    PushInt(i32),
+    /// Replace the string on top of the stack with its length in bytes
+    Length,
+    /// Replace two numbers on top of the stack with the result of the operator
+    Binary(char),
+    /// Replace the number on top of the stack with the result of the operator
+    Unary(char),
+    /// Increment the first two parameters, only the first time
+    Increment,
+    /// XOR the first two parameters with 0140 (legacy termcap `%n`)
+    XorParams,
+    /// Output the value on top of the stack using the format
+    Format(char, Flags),
+    /// Pop the condition, jump to the target if it's false
+    Then(usize),
+    /// Jump to the target
+    Else(usize),
+    /// Output padding for the delay
+    Pad(Delay),
+}
+
+/// Capability compiled for repeated expansion
+///
+/// Use `CompiledCap::compile()` to create it and `CompiledCap::expand()` to
+/// expand it. `ExpandContext::compile()` and `ExpandContext::run()` do the
+/// same.
+#[derive(Clone, Debug, Default)]
+pub struct CompiledCap {
+    /// Decoded operations
+    ops: Vec<Op>,
+    /// Literal bytes referenced by `Op::Literal`
+    literals: Vec<u8>,
+    /// Whether a conditional is not terminated by `%;`
+    unterminated: bool,
+}
+
+impl CompiledCap {
+    /// Compile a capability for repeated expansion
+    ///
+    /// The capability is decoded once, so that it can be expanded by
+    /// `expand()` many times without parsing it again. Malformed format
+    /// specifications and constants are reported by this function.
+    pub fn compile(cap: &[u8]) -> Result<Self, Error> {
+        Self::new(cap)
+    }
+
+    /// Find the parameters referenced by the capability and their types
+    ///
+    /// Same as `required_params()`.
+    #[must_use]
+    pub fn param_usage(&self) -> ParamUsage {
+        let mut usage = ParamUsage::default();
+        // Parameter indices of the stack elements, `None` for other values
+        let mut stack: Vec<Option<usize>> = vec![];
+        for op in &self.ops {
+            match *op {
+                Op::PushParam(index) => {
+                    usage.count = usage.count.max(index + 1);
+                    stack.push(Some(index));
+                }
+                Op::GetVar(_) | Op::PushInt(_) => stack.push(None),
+                Op::Length => {
+                    usage.infer(stack.pop().flatten(), ParamType::String);
+                    stack.push(None);
+                }
+                Op::Format('s', _) => usage.infer(stack.pop().flatten(), ParamType::String),
+                Op::Char | Op::Format(..) | Op::Then(_) => {
+                    usage.infer(stack.pop().flatten(), ParamType::Number);
+                }
+                Op::Unary(_) => {
+                    usage.infer(stack.pop().flatten(), ParamType::Number);
+                    stack.push(None);
+                }
+                Op::Binary(_) => {
+                    usage.infer(stack.pop().flatten(), ParamType::Number);
+                    usage.infer(stack.pop().flatten(), ParamType::Number);
+                    stack.push(None);
+                }
+                Op::Increment | Op::XorParams => {
+                    usage.infer(Some(0), ParamType::Number);
+                    usage.infer(Some(1), ParamType::Number);
+                }
+                Op::SetVar(_) => _ = stack.pop().flatten(),
+                Op::Literal(_) | Op::Else(_) | Op::Pad(_) => {}
+            }
+        }
+        usage
+    }
+
+    /// Iterate over the delays in the capability
+    pub fn delays(&self) -> impl Iterator<Item = Delay> + '_ {
+        self.ops.iter().filter_map(|op| match op {
+            Op::Pad(delay) => Some(*delay),
+            _ => None,
+        })
+    }
+
+    /// Expand the compiled capability in the given context
+    ///
+    /// # Arguments
+    /// * `context` - context keeping the variables and output settings
+    /// * `params`  - vector of params for %p1 etc
+    pub fn expand(
+        &self,
+        context: &mut ExpandContext,
+        params: &[Parameter<'_>],
+    ) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::with_capacity(self.literals.len());
+        context.run_with_trace(self, LazyParams::new(slice_provider(params)), &mut output)?;
+        Ok(output)
+    }
+
+    fn new(cap: &[u8]) -> Result<Self, Error> {
+        Self::new_traced(cap, &mut vec![], &mut vec![]).map_err(|(_, err)| err)
+    }
+
+    /// Compile the capability, record the offsets of the operations
+    ///
+    /// `offsets` receives the offset of the sequence that produced each
+    /// operation, `markers` receives the conditional operators with their
+    /// offsets. Errors are reported with the offset of the failed sequence.
+    fn new_traced(
+        cap: &[u8],
+        offsets: &mut Vec<usize>,
+        markers: &mut Vec<(char, usize)>,
+    ) -> Result<Self, (usize, Error)> {
+        let mut prog = Self {
+            ops: vec![],
+            literals: vec![],
+            unterminated: false,
+        };
+
+        // Conditional operators and the indices of the following operations
+        let mut conditionals = vec![];
+
+        let mut state = States::Nothing;
+        let mut start = 0;
+
+        for (index, &c) in cap.iter().enumerate() {
+            // `$` not followed by `<` is a literal, the next sequence starts here.
+            if state == States::Nothing || (state == States::Dollar && c != b'<') {
+                start = index;
+            }
+            state = prog
+                .step(state, c, &mut conditionals)
+                .map_err(|err| (start, err))?;
+            offsets.resize(prog.ops.len(), start);
+            if let Some(&(kind, _)) = conditionals.get(markers.len()) {
+                markers.push((kind, start));
+            }
+        }
+
+        match state {
+            States::PushParam => return Err((start, Error::MissingParameterIndex)),
+            States::Dollar => prog.push_literal(b'$', &conditionals),
+            States::Delay(..) => return Err((start, Error::MalformedDelay)),
+            _ => {}
+        }
+        offsets.resize(prog.ops.len(), start);
+
+        prog.resolve_jumps(&conditionals);
+        let count = |kind| conditionals.iter().filter(|&&(k, _)| k == kind).count();
+        prog.unterminated =
+            count('?') > count(';') || matches!(conditionals.last(), Some(('t' | 'e', _)));
+        Ok(prog)
+    }
+
+    fn validate(cap: &[u8]) -> Result<(), (usize, Error)> {
+        let mut offsets = vec![];
+        let mut markers = vec![];
+        let prog = Self::new_traced(cap, &mut offsets, &mut markers)?;
+
+        let mut open = vec![];
+        for &(kind, offset) in &markers {
+            match kind {
+                '?' => open.push(offset),
+                ';' if open.pop().is_none() => return Err((offset, Error::UnbalancedConditional)),
+                _ => {}
+            }
+        }
+        if let Some(&offset) = open.first() {
+            return Err((offset, Error::UnbalancedConditional));
+        }
+
+        // Follow both branches of every `%t` with the stack depth.
+        let mut pending = vec![(0, 0)];
+        let mut visited = HashSet::new();
+        while let Some((mut pc, mut depth)) = pending.pop() {
+            while let Some(op) = prog.ops.get(pc) {
+                if !visited.insert((pc, depth)) {
+                    break;
+                }
+                let (name, pops, pushes) = match *op {
+                    Op::PushParam(_) | Op::GetVar(_) | Op::PushInt(_) => (' ', 0, 1),
+                    Op::Char => ('c', 1, 0),
+                    Op::SetVar(name) if !name.is_ascii_alphabetic() => {
+                        return Err((offsets[pc], Error::InvalidVariableName(name)));
                     }
-                },
-                'x' => match flags.precision {
-                    Some(precision) => {
-                        if flags.alternate && d != 0 {
-                            format!("0x{d:0prec$x}", prec = precision.into())
-                        } else {
-                            format!("{d:0prec$x}", prec = precision.into())
-                        }
+                    Op::SetVar(_) => ('P', 1, 0),
+                    Op::Length => ('l', 1, 1),
+                    Op::Binary(cur) => (cur, 2, 1),
+                    Op::Unary(cur) => (cur, 1, 1),
+                    Op::Format(cur, _) => (cur, 1, 0),
+                    Op::Then(_) => ('t', 1, 0),
+                    Op::Literal(_) | Op::Increment | Op::XorParams | Op::Else(_) | Op::Pad(_) => {
+                        (' ', 0, 0)
                     }
-                    None => {
-                        if flags.alternate && d != 0 {
-                            format!("0x{d:x}")
-                        } else {
-                            format!("{d:x}")
-                        }
+                };
+                if depth < pops {
+                    return Err((offsets[pc], Error::StackUnderflow(name)));
+                }
+                depth = depth - pops + pushes;
+                pc = match *op {
+                    Op::Then(target) => {
+                        pending.push((target, depth));
+                        pc + 1
                     }
-                },
-                'X' => match flags.precision {
-                    Some(precision) => {
-                        if flags.alternate && d != 0 {
-                            format!("0X{d:0prec$X}", prec = precision.into())
-                        } else {
-                            format!("{d:0prec$X}", prec = precision.into())
+                    Op::Else(target) => target,
+                    _ => pc + 1,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Process a byte of the capability, return the state for the following byte
+    fn step(
+        &mut self,
+        state: States,
+        c: u8,
+        conditionals: &mut Vec<(char, usize)>,
+    ) -> Result<States, Error> {
+        let cur = c as char;
+        Ok(match state {
+            States::Nothing => match cur {
+                '%' => States::Percent,
+                '$' => States::Dollar,
+                _ => {
+                    self.push_literal(c, conditionals);
+                    States::Nothing
+                }
+            },
+            // `$` not followed by `<` is a literal character.
+            States::Dollar => {
+                if cur == '<' {
+                    States::Delay(Delay::default(), DelayState::Open)
+                } else {
+                    self.push_literal(b'$', conditionals);
+                    match cur {
+                        '%' => States::Percent,
+                        '$' => States::Dollar,
+                        _ => {
+                            self.push_literal(c, conditionals);
+                            States::Nothing
                         }
                     }
-                    None => {
-                        if flags.alternate && d != 0 {
-                            format!("0X{d:X}")
-                        } else {
-                            format!("{d:X}")
-                        }
+                }
+            }
+            // Only one digit after the decimal point is significant.
+            States::Delay(mut delay, delay_state) => match (cur, delay_state) {
+                ('0'..='9', DelayState::Open | DelayState::Integer) => {
+                    let digit = c - b'0';
+                    delay.tenths = delay
+                        .tenths
+                        .saturating_mul(10)
+                        .saturating_add(u32::from(digit) * 10);
+                    States::Delay(delay, DelayState::Integer)
+                }
+                ('.', DelayState::Open | DelayState::Integer) => {
+                    States::Delay(delay, DelayState::Point)
+                }
+                ('0'..='9', DelayState::Point) => {
+                    delay.tenths = delay.tenths.saturating_add(u32::from(c - b'0'));
+                    States::Delay(delay, DelayState::Decimals)
+                }
+                ('0'..='9', DelayState::Decimals) => States::Delay(delay, delay_state),
+                ('*' | '/' | '>', DelayState::Open) => return Err(Error::MalformedDelay),
+                ('*', _) => {
+                    delay.proportional = true;
+                    States::Delay(delay, DelayState::Suffix)
+                }
+                ('/', _) => {
+                    delay.mandatory = true;
+                    States::Delay(delay, DelayState::Suffix)
+                }
+                ('>', _) => self.push_op(Op::Pad(delay)),
+                _ => return Err(Error::MalformedDelay),
+            },
+            States::Percent => match cur {
+                '%' => {
+                    self.push_literal(c, conditionals);
+                    States::Nothing
+                }
+                'c' => self.push_op(Op::Char),
+                'p' => States::PushParam,
+                'P' => States::SetVar,
+                'g' => States::GetVar,
+                '\'' => States::CharConstant,
+                '{' => States::IntConstant(0),
+                'l' => self.push_op(Op::Length),
+                '+' | '-' | '*' | '/' | '|' | '&' | '^' | 'm' | '=' | '>' | '<' | 'A' | 'O' => {
+                    self.push_op(Op::Binary(cur))
+                }
+                '!' | '~' | 'B' | 'D' => self.push_op(Op::Unary(cur)),
+                'i' => self.push_op(Op::Increment),
+                'n' => self.push_op(Op::XorParams),
+
+                // printf-style support for %doxXs, `-` and `+` need `:` first
+                ':' => States::FormatPattern(Flags::default(), FormatState::Flags),
+                'd' | 'o' | 'x' | 'X' | 's' | '#' | ' ' | '.' | '0'..='9' => {
+                    self.format_pattern(Flags::default(), FormatState::Flags, cur)?
+                }
+
+                // conditionals, jump targets are resolved at the end
+                '?' | ';' => {
+                    conditionals.push((cur, self.ops.len()));
+                    States::Nothing
+                }
+                't' => {
+                    conditionals.push((cur, self.ops.len()));
+                    self.push_op(Op::Then(0))
+                }
+                'e' => {
+                    conditionals.push((cur, self.ops.len()));
+                    self.push_op(Op::Else(0))
+                }
+                c => return Err(Error::UnrecognizedFormatOption(c)),
+            },
+            States::PushParam => {
+                // params are 1-indexed
+                let index = match cur {
+                    '1'..='9' => cur as usize - '1' as usize,
+                    _ => return Err(Error::InvalidParameterIndex(cur)),
+                };
+                self.push_op(Op::PushParam(index))
+            }
+            // The name is checked when the value is popped.
+            States::SetVar => self.push_op(Op::SetVar(cur)),
+            States::GetVar => match cur {
+                'A'..='Z' | 'a'..='z' => self.push_op(Op::GetVar(cur)),
+                _ => return Err(Error::InvalidVariableName(cur)),
+            },
+            States::CharConstant => {
+                if cur == '\\' {
+                    States::CharEscape
+                } else {
+                    self.push_op(Op::PushInt(i32::from(c)));
+                    States::CharClose
+                }
+            }
+            States::CharEscape => {
+                let value = match cur {
+                    'n' => b'\n',
+                    'r' => b'\r',
+                    't' => b'\t',
+                    'b' => 0x08,
+                    'f' => 0x0c,
+                    's' => b' ',
+                    '0' => 0,
+                    '\\' | '\'' => c,
+                    _ => return Err(Error::MalformedCharacterConstant),
+                };
+                self.push_op(Op::PushInt(i32::from(value)));
+                States::CharClose
+            }
+            States::CharClose => {
+                if cur != '\'' {
+                    return Err(Error::MalformedCharacterConstant);
+                }
+                States::Nothing
+            }
+            States::IntConstant(i) => {
+                if cur == '}' {
+                    self.push_op(Op::PushInt(i))
+                } else if let Some(digit) = cur.to_digit(10) {
+                    match i
+                        .checked_mul(10)
+                        .and_then(|i_ten| i_ten.checked_add(digit as i32))
+                    {
+                        Some(i) => States::IntConstant(i),
+                        None => return Err(Error::IntegerConstantOverflow),
                     }
-                },
-                _ => return Err(Error::FormatTypeMismatch),
+                } else {
+                    return Err(Error::MalformedIntegerConstant);
+                }
             }
-            .into_bytes()
+            States::FormatPattern(flags, fstate) => self.format_pattern(flags, fstate, cur)?,
+        })
+    }
+
+    /// Process a character of a format specification
+    ///
+    /// Both `%d` and `%:d` are handled here, starting with the default flags.
+    fn format_pattern(
+        &mut self,
+        mut flags: Flags,
+        mut fstate: FormatState,
+        cur: char,
+    ) -> Result<States, Error> {
+        match (fstate, cur) {
+            (_, 'd' | 'o' | 'x' | 'X' | 's') => return Ok(self.push_op(Op::Format(cur, flags))),
+            (FormatState::Flags, '#') => {
+                flags.alternate = true;
+            }
+            (FormatState::Flags, '-') => {
+                flags.left = true;
+            }
+            (FormatState::Flags, '+') => {
+                flags.sign = SignFlags::Plus;
+            }
+            (FormatState::Flags, ' ') => {
+                flags.sign = SignFlags::Space;
+            }
+            (FormatState::Flags, '0'..='9') => {
+                flags.width = cur as u16 - '0' as u16;
+                fstate = FormatState::Width;
+            }
+            (FormatState::Width, '0'..='9') => {
+                flags.width = match flags
+                    .width
+                    .checked_mul(10)
+                    .and_then(|w| w.checked_add(cur as u16 - '0' as u16))
+                {
+                    Some(width) => width,
+                    None => return Err(Error::FormatWidthOverflow),
+                }
+            }
+            (FormatState::Width | FormatState::Flags, '.') => {
+                fstate = FormatState::Precision;
+            }
+            (FormatState::Precision, '0'..='9') => {
+                flags.precision = match flags
+                    .precision
+                    .unwrap_or(0)
+                    .checked_mul(10)
+                    .and_then(|w| w.checked_add(cur as u16 - '0' as u16))
+                {
+                    Some(precision) => Some(precision),
+                    None => return Err(Error::FormatPrecisionOverflow),
+                }
+            }
+            _ => return Err(Error::UnrecognizedFormatOption(cur)),
+        }
+        Ok(States::FormatPattern(flags, fstate))
+    }
+
+    /// Append an operation, return the state for the following byte
+    fn push_op(&mut self, op: Op) -> States {
+        self.ops.push(op);
+        States::Nothing
+    }
+
+    /// Append a literal byte, extending the last literal operation if possible
+    ///
+    /// The last operation is not extended if it's followed by a conditional
+    /// operator, as it could be a jump target.
+    fn push_literal(&mut self, byte: u8, conditionals: &[(char, usize)]) {
+        let start = self.literals.len();
+        self.literals.push(byte);
+        let jump_target = conditionals.last().map(|&(_, index)| index);
+        if jump_target != Some(self.ops.len())
+            && let Some(Op::Literal(range)) = self.ops.last_mut()
+            && range.end == start
+        {
+            range.end += 1;
+        } else {
+            self.ops.push(Op::Literal(start..start + 1));
+        }
+    }
+
+    /// Set jump targets for `%t` and `%e`
+    ///
+    /// `%t` jumps past the matching `%e` or to the matching `%;`, `%e` jumps
+    /// to the matching `%;`. If there is no match, the jump goes to the end.
+    fn resolve_jumps(&mut self, conditionals: &[(char, usize)]) {
+        for (i, &(kind, index)) in conditionals.iter().enumerate() {
+            if kind != 't' && kind != 'e' {
+                continue;
+            }
+            let mut level = 0;
+            let mut target = self.ops.len();
+            for &(next_kind, next_index) in &conditionals[i + 1..] {
+                match next_kind {
+                    '?' => level += 1,
+                    ';' if level == 0 => {
+                        target = next_index;
+                        break;
+                    }
+                    ';' => level -= 1,
+                    'e' if level == 0 && kind == 't' => {
+                        target = next_index + 1;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            if let Op::Then(jump) | Op::Else(jump) = &mut self.ops[index] {
+                *jump = target;
+            }
+        }
+    }
+}
+
+/// Output of an expansion with an optional size limit
+struct Output<'w, W> {
+    out: &'w mut W,
+    /// Number of bytes that can still be written, `None` if unlimited
+    remaining: Option<usize>,
+}
+
+impl<W: Write> Output<'_, W> {
+    /// Write bytes to the output
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.check(bytes.len())?;
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= bytes.len();
+        }
+        self.out
+            .write_all(bytes)
+            .map_err(|err| Error::IO(err.kind()))
+    }
+
+    /// Check that the given number of bytes can be written
+    const fn check(&self, size: usize) -> Result<(), Error> {
+        match self.remaining {
+            Some(remaining) if size > remaining => Err(Error::OutputTooLarge),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Expected type of a parameter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParamType {
+    Number,
+    String,
+}
+
+/// Parameters referenced by a capability
+///
+/// Types are inferred from the operators applied to the parameters right after
+/// they are pushed. Conditionals are not followed, so the result is a best
+/// effort for complex capabilities.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ParamUsage {
+    /// Highest parameter index used, 1-based
+    count: usize,
+    /// Inferred types, `None` if unknown or inconsistent
+    types: [Option<ParamType>; 9],
+    /// Parameters used with different types
+    mixed: u16,
+}
+
+impl ParamUsage {
+    /// Return the highest parameter index used, 0 if no parameters are used
+    #[must_use]
+    pub const fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Return the expected type of the parameter with the 1-based index
+    ///
+    /// Returns `None` if the parameter is unused, its type cannot be inferred
+    /// or it's used with different types.
+    #[must_use]
+    pub fn param_type(&self, index: usize) -> Option<ParamType> {
+        let index = index.checked_sub(1)?;
+        self.types.get(index).copied().flatten()
+    }
+
+    fn infer(&mut self, param: Option<usize>, param_type: ParamType) {
+        let Some(index) = param else {
+            return;
+        };
+        if self.mixed & (1 << index) != 0 {
+            return;
+        }
+        match self.types[index] {
+            None => self.types[index] = Some(param_type),
+            Some(known) if known == param_type => {}
+            Some(_) => {
+                self.types[index] = None;
+                self.mixed |= 1 << index;
+            }
+        }
+    }
+}
+
+/// Find the parameters referenced by a capability and their types
+///
+/// The capability is compiled but not expanded.
+pub fn required_params(cap: &[u8]) -> Result<ParamUsage, Error> {
+    Ok(CompiledCap::new(cap)?.param_usage())
+}
+
+/// Summary of the operations used by a capability
+///
+/// Unlike `expand_traced()`, all variables referenced by the capability are
+/// reported, not only those accessed for the given parameters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CapInfo {
+    params: ParamUsage,
+    variables: VarTrace,
+    conditionals: bool,
+    delays: bool,
+    increment: bool,
+}
+
+impl CapInfo {
+    /// Return the parameters referenced by the capability
+    #[must_use]
+    pub const fn params(&self) -> ParamUsage {
+        self.params
+    }
+
+    /// Return the variables read and written by the capability
+    #[must_use]
+    pub const fn variables(&self) -> VarTrace {
+        self.variables
+    }
+
+    /// Check if the capability has conditionals
+    #[must_use]
+    pub const fn uses_conditionals(&self) -> bool {
+        self.conditionals
+    }
+
+    /// Check if the capability has delays
+    #[must_use]
+    pub const fn uses_delays(&self) -> bool {
+        self.delays
+    }
+
+    /// Check if the capability increments the parameters with `%i`
+    #[must_use]
+    pub const fn uses_increment(&self) -> bool {
+        self.increment
+    }
+}
+
+/// Find the parameters, variables and operations used by a capability
+///
+/// The capability is compiled but not expanded.
+pub fn analyze(cap: &[u8]) -> Result<CapInfo, Error> {
+    let prog = CompiledCap::new(cap)?;
+    let mut info = CapInfo {
+        params: prog.param_usage(),
+        ..CapInfo::default()
+    };
+    for op in &prog.ops {
+        match *op {
+            Op::GetVar(name) => info.variables.read |= VarTrace::mask(name),
+            Op::SetVar(name) => info.variables.written |= VarTrace::mask(name),
+            Op::Then(_) | Op::Else(_) => info.conditionals = true,
+            Op::Pad(_) => info.delays = true,
+            Op::Increment => info.increment = true,
+            _ => {}
+        }
+    }
+    Ok(info)
+}
+
+/// Variables accessed during an expansion
+///
+/// Static variables are named `A` to `Z`, dynamic variables are named `a` to
+/// `z`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VarTrace {
+    read: u64,
+    written: u64,
+}
+
+impl VarTrace {
+    /// Bit mask for a valid variable name
+    const fn mask(name: char) -> u64 {
+        match name {
+            'A'..='Z' => 1 << (name as u8 - b'A'),
+            'a'..='z' => 1 << (name as u8 - b'a' + 32),
+            _ => 0,
+        }
+    }
+
+    /// Return the names of the variables in the mask
+    fn names(mask: u64) -> impl Iterator<Item = char> {
+        ('A'..='Z')
+            .chain('a'..='z')
+            .filter(move |name| mask & Self::mask(*name) != 0)
+    }
+
+    /// Check if the variable was read
+    #[must_use]
+    pub const fn was_read(&self, name: char) -> bool {
+        self.read & Self::mask(name) != 0
+    }
+
+    /// Check if the variable was written
+    #[must_use]
+    pub const fn was_written(&self, name: char) -> bool {
+        self.written & Self::mask(name) != 0
+    }
+
+    /// Return the names of the variables that were read
+    pub fn read(&self) -> impl Iterator<Item = char> {
+        Self::names(self.read)
+    }
+
+    /// Return the names of the variables that were written
+    pub fn written(&self) -> impl Iterator<Item = char> {
+        Self::names(self.written)
+    }
+}
+
+#[derive(Copy, PartialEq, Clone, Default, Debug)]
+enum SignFlags {
+    #[default]
+    Empty,
+    Space,
+    Plus,
+}
+
+#[derive(Copy, PartialEq, Clone, Default, Debug)]
+struct Flags {
+    width: u16,
+    precision: Option<u16>,
+    alternate: bool,
+    left: bool,
+    sign: SignFlags,
+}
+
+fn format(val: &Parameter<'_>, op: char, flags: Flags) -> Result<Vec<u8>, Error> {
+    let mut s = match *val {
+        Parameter::Number(d) => {
+            match op {
+                'd' => match flags.precision {
+                    Some(precision) => {
+                        if d < 0 {
+                            format!("{d:0prec$}", prec = usize::from(precision + 1))
+                        } else {
+                            match flags.sign {
+                                SignFlags::Empty => {
+                                    format!("{d:0prec$}", prec = precision.into())
+                                }
+                                SignFlags::Space => {
+                                    format!(" {d:0prec$}", prec = precision.into())
+                                }
+                                SignFlags::Plus => {
+                                    format!("{d:+0prec$}", prec = usize::from(precision + 1))
+                                }
+                            }
+                        }
+                    }
+                    None => {
+                        if d < 0 {
+                            format!("{d}")
+                        } else {
+                            match flags.sign {
+                                SignFlags::Empty => {
+                                    format!("{d}")
+                                }
+                                SignFlags::Space => {
+                                    format!(" {d}")
+                                }
+                                SignFlags::Plus => {
+                                    format!("{d:+}")
+                                }
+                            }
+                        }
+                    }
+                },
+                'o' => match flags.precision {
+                    Some(precision) => {
+                        if flags.alternate {
+                            // Leading octal zero counts against precision.
+                            format!("0{d:0prec$o}", prec = precision.saturating_sub(1).into())
+                        } else {
+                            format!("{d:0prec$o}", prec = precision.into())
+                        }
+                    }
+                    None => {
+                        if flags.alternate {
+                            format!("0{d:o}")
+                        } else {
+                            format!("{d:o}")
+                        }
+                    }
+                },
+                'x' => match flags.precision {
+                    Some(precision) => {
+                        if flags.alternate && d != 0 {
+                            format!("0x{d:0prec$x}", prec = precision.into())
+                        } else {
+                            format!("{d:0prec$x}", prec = precision.into())
+                        }
+                    }
+                    None => {
+                        if flags.alternate && d != 0 {
+                            format!("0x{d:x}")
+                        } else {
+                            format!("{d:x}")
+                        }
+                    }
+                },
+                'X' => match flags.precision {
+                    Some(precision) => {
+                        if flags.alternate && d != 0 {
+                            format!("0X{d:0prec$X}", prec = precision.into())
+                        } else {
+                            format!("{d:0prec$X}", prec = precision.into())
+                        }
+                    }
+                    None => {
+                        if flags.alternate && d != 0 {
+                            format!("0X{d:X}")
+                        } else {
+                            format!("{d:X}")
+                        }
+                    }
+                },
+                _ => return Err(Error::FormatTypeMismatch),
+            }
+            .into_bytes()
+        }
+        Parameter::String(_) | Parameter::Str(_) => match (op, val.as_bytes()) {
+            ('s', Some(s)) => {
+                let mut length = s.len();
+                if let Some(precision) = flags.precision {
+                    length = length.min(usize::from(precision));
+                }
+                s[..length].to_vec()
+            }
+            _ => return Err(Error::FormatTypeMismatch),
+        },
+    };
+    if usize::from(flags.width) > s.len() {
+        let n = usize::from(flags.width) - s.len();
+        if flags.left {
+            s.extend(repeat_n(b' ', n));
+        } else {
+            let mut s_ = Vec::with_capacity(usize::from(flags.width));
+            s_.extend(repeat_n(b' ', n));
+            s_.extend(s);
+            s = s_;
+        }
+    }
+    Ok(s)
+}
+
+impl Default for ExpandContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Builder for `ExpandContext`
+///
+/// Settings that are not specified have the same defaults as in
+/// `ExpandContext::new()`. Each method has the same effect as the
+/// corresponding setter of `ExpandContext`.
+#[derive(Default)]
+pub struct ExpandContextBuilder {
+    context: ExpandContext,
+}
+
+impl ExpandContextBuilder {
+    /// Set the output speed for padding, see `set_baud_rate()`
+    #[must_use]
+    pub const fn baud_rate(mut self, baud_rate: Option<u32>) -> Self {
+        self.context.baud_rate = baud_rate;
+        self
+    }
+
+    /// Set the character used for padding, see `set_pad_char()`
+    #[must_use]
+    pub const fn pad_char(mut self, pad_char: u8) -> Self {
+        self.context.pad_char = pad_char;
+        self
+    }
+
+    /// Set the number of affected lines, see `set_affected_lines()`
+    #[must_use]
+    pub const fn affected_lines(mut self, affected_lines: u32) -> Self {
+        self.context.affected_lines = affected_lines;
+        self
+    }
+
+    /// Set whether XON/XOFF flow control is used, see `set_xon_xoff()`
+    #[must_use]
+    pub const fn xon_xoff(mut self, xon_xoff: bool) -> Self {
+        self.context.xon_xoff = xon_xoff;
+        self
+    }
+
+    /// Set the limit for the size of the output, see `set_max_output()`
+    #[must_use]
+    pub const fn output_limit(mut self, limit: Option<usize>) -> Self {
+        self.context.max_output = limit;
+        self
+    }
+
+    /// Set the limit for the capability length, see `set_max_capability_len()`
+    #[must_use]
+    pub const fn max_capability_len(mut self, limit: Option<usize>) -> Self {
+        self.context.max_capability_len = limit;
+        self
+    }
+
+    /// Set whether type mismatches are errors, the opposite of `set_lenient()`
+    #[must_use]
+    pub const fn strict_types(mut self, strict: bool) -> Self {
+        self.context.lenient = !strict;
+        self
+    }
+
+    /// Set whether unterminated conditionals are rejected, see
+    /// `set_strict_conditionals()`
+    #[must_use]
+    pub const fn strict_conditionals(mut self, strict: bool) -> Self {
+        self.context.strict_conditionals = strict;
+        self
+    }
+
+    /// Set whether `%c` outputs 0200 for 0, see `set_nul_as_0x80()`
+    #[must_use]
+    pub const fn nul_as_0x80(mut self, nul_as_0x80: bool) -> Self {
+        self.context.nul_as_0x80 = nul_as_0x80;
+        self
+    }
+
+    /// Return the configured context
+    #[must_use]
+    pub fn build(self) -> ExpandContext {
+        self.context
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        io::{self, Write},
+    };
+
+    use super::{
+        CompiledCap, DEFAULT_MAX_OUTPUT, Error, ExpandContext, ParamType, Parameter, VarTrace,
+        analyze, required_params, slice_provider,
+    };
+
+    /// Compare the result of `expand()` to the expected string
+    fn assert_str(actual: Result<Vec<u8>, Error>, expected: &str) {
+        assert_eq!(str::from_utf8(&actual.unwrap()).unwrap(), expected);
+    }
+
+    #[test]
+    fn multiple_parameters() {
+        let mut expand_context = ExpandContext::default();
+        assert_str(
+            expand_context.expand(
+                b"%p1%p2%p3%p4%p5%p6%p7%p8%p9%d%d%d%d%d%s%s%s%d",
+                &[
+                    Parameter::from(1),
+                    Parameter::from(b"Two"),
+                    Parameter::from(b"Three".as_slice()),
+                    Parameter::from("Four"),
+                    Parameter::from(5),
+                    Parameter::from(6),
+                    Parameter::from(7),
+                    Parameter::from(8),
+                    Parameter::from(9),
+                ],
+            ),
+            "98765FourThreeTwo1",
+        );
+    }
+
+    #[test]
+    fn owned_and_borrowed_strings() {
+        let mut expand_context = ExpandContext::new();
+        let borrowed = Parameter::from("Hello");
+        let owned = Parameter::String(b"Hello".to_vec());
+        for param in [borrowed, owned] {
+            assert_str(
+                expand_context.expand(b"%p1%s %p1%.3s %p1%l%d", &[param]),
+                "Hello Hel 5",
+            );
+        }
+    }
+
+    #[test]
+    fn borrowed_string_in_static_variable() {
+        let mut expand_context = ExpandContext::new();
+        {
+            let value = String::from("Temporary");
+            let params = [Parameter::from(value.as_str())];
+            assert_str(expand_context.expand(b"%p1%PS", &params), "");
+        }
+        assert_str(expand_context.expand(b"%gS%s", &[]), "Temporary");
+    }
+
+    #[test]
+    fn expand_into_writer() {
+        let mut expand_context = ExpandContext::new();
+        let mut output = io::Cursor::new(vec![]);
+        expand_context
+            .expand_into(b"[%p1%d]", &[Parameter::from(5)], &mut output)
+            .unwrap();
+        expand_context
+            .expand_into(b"[%p1%s]", &[Parameter::from("x")], &mut output)
+            .unwrap();
+        assert_eq!(output.into_inner(), b"[5][x]");
+    }
+
+    #[test]
+    fn expand_into_vector() {
+        let mut expand_context = ExpandContext::new();
+        let mut output = b"<".to_vec();
+        expand_context
+            .expand_into(b"%p1%d,", &[Parameter::from(1)], &mut output)
+            .unwrap();
+        expand_context
+            .expand_into(b"%p1%d>", &[Parameter::from(2)], &mut output)
+            .unwrap();
+        assert_eq!(output, b"<1,2>");
+
+        assert_eq!(
+            expand_context.expand_into(b"[%p1%d]", &[Parameter::from("x")], &mut output),
+            Err(Error::FormatTypeMismatch)
+        );
+        assert_eq!(output, b"<1,2>[");
+    }
+
+    #[test]
+    fn expand_append() {
+        let mut expand_context = ExpandContext::new();
+        let mut output = vec![];
+        for row in 1..=3 {
+            expand_context
+                .expand_append(b"\x1b[%p1%dH", &[Parameter::from(row)], &mut output)
+                .unwrap();
+        }
+        assert_eq!(output, b"\x1b[1H\x1b[2H\x1b[3H");
+
+        assert_eq!(
+            expand_context.expand_append(b"x%p1%d", &[Parameter::from("x")], &mut output),
+            Err(Error::FormatTypeMismatch)
+        );
+        assert_eq!(output, b"\x1b[1H\x1b[2H\x1b[3Hx");
+    }
+
+    #[test]
+    fn expand_into_failing_writer() {
+        struct FailingWriter;
+
+        impl Write for FailingWriter {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::Error::from(io::ErrorKind::BrokenPipe))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut expand_context = ExpandContext::new();
+        assert_eq!(
+            expand_context.expand_into(b"abc", &[], &mut FailingWriter),
+            Err(Error::IO(io::ErrorKind::BrokenPipe))
+        );
+    }
+
+    #[test]
+    fn static_variable_access() {
+        let mut expand_context = ExpandContext::new();
+        expand_context.set_static('B', Parameter::from(42)).unwrap();
+        expand_context
+            .set_static('Z', Parameter::from("zed"))
+            .unwrap();
+        assert_str(expand_context.expand(b"%gB%d %gZ%s", &[]), "42 zed");
+        assert!(matches!(
+            expand_context.get_static('B'),
+            Ok(Parameter::Number(42))
+        ));
+
+        expand_context.expand(b"%{7}%PC", &[]).unwrap();
+        assert!(matches!(
+            expand_context.get_static('C'),
+            Ok(Parameter::Number(7))
+        ));
+
+        assert_eq!(
+            expand_context.set_static('a', Parameter::from(1)),
+            Err(Error::InvalidVariableName('a'))
+        );
+        assert!(matches!(
+            expand_context.get_static('['),
+            Err(Error::InvalidVariableName('['))
+        ));
+
+        expand_context.reset();
+        for name in 'A'..='Z' {
+            assert!(matches!(
+                expand_context.get_static(name),
+                Ok(Parameter::Number(0))
+            ));
+        }
+    }
+
+    #[test]
+    fn compile_and_run() {
+        let cap = b"\x1b[%i%p1%d;%p2%dH";
+        let prog = ExpandContext::compile(cap).unwrap();
+        let mut expand_context = ExpandContext::new();
+        for (row, column) in [(0, 0), (5, 10), (23, 79)] {
+            let params = [Parameter::from(row), Parameter::from(column)];
+            let expected = expand_context.expand(cap, &params).unwrap();
+            assert_eq!(expand_context.run(&prog, &params), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn compile_once_expand_many() {
+        let caps: [&[u8]; 4] = [
+            b"\x1b[%i%p1%d;%p2%dH",
+            b"\x1b[%?%p1%{8}%<%t3%p1%d%e%p1%{16}%<%t9%p1%{8}%-%d%e38;5;%p1%d%;m",
+            b"%p1%Pa%ga%ga%+%:-5d",
+            b"%p1%s=%p1%l%x",
+        ];
+        let mut direct_context = ExpandContext::new();
+        let mut compiled_context = ExpandContext::new();
+        for cap in caps {
+            let compiled = CompiledCap::compile(cap).unwrap();
+            for n in 0..300 {
+                let params = [Parameter::from(n), Parameter::from(n * 7 % 100)];
+                let params_str = [Parameter::from("abc")];
+                let params = if cap.starts_with(b"%p1%s") {
+                    &params_str[..]
+                } else {
+                    &params[..]
+                };
+                assert_eq!(
+                    compiled.expand(&mut compiled_context, params),
+                    direct_context.expand(cap, params)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn compile_conditionals() {
+        let cap = b"%?%p1%t+%?%p2%t+%e-%;%e-%?%p2%t+%e-%;%;!";
+        let prog = CompiledCap::compile(cap).unwrap();
+        let mut expand_context = ExpandContext::new();
+        for (param1, param2, expected) in
+            [(0, 0, "--!"), (0, 1, "-+!"), (1, 0, "+-!"), (1, 1, "++!")]
+        {
+            let params = [Parameter::from(param1), Parameter::from(param2)];
+            assert_str(prog.expand(&mut expand_context, &params), expected);
+        }
+    }
+
+    #[test]
+    fn compile_errors() {
+        assert!(matches!(
+            CompiledCap::compile(b"%p1%:^x"),
+            Err(Error::UnrecognizedFormatOption('^'))
+        ));
+        assert!(matches!(
+            CompiledCap::compile(b"%g1"),
+            Err(Error::InvalidVariableName('1'))
+        ));
+        assert!(matches!(
+            CompiledCap::compile(b"%{12a}"),
+            Err(Error::MalformedIntegerConstant)
+        ));
+    }
+
+    #[test]
+    fn parameter_from_integers() {
+        assert_eq!(Parameter::from(255u8), Parameter::Number(255));
+        assert_eq!(Parameter::from(65535u16), Parameter::Number(65535));
+        assert_eq!(Parameter::from(1234usize), Parameter::Number(1234));
+        assert_eq!(
+            Parameter::from(i32::MAX as usize),
+            Parameter::Number(i32::MAX)
+        );
+        assert_eq!(
+            Parameter::from(i32::MAX as usize + 1),
+            Parameter::Number(i32::MAX)
+        );
+        assert_eq!(Parameter::from(usize::MAX), Parameter::Number(i32::MAX));
+        assert_eq!(Parameter::from(true), Parameter::Number(1));
+        assert_eq!(Parameter::from(false), Parameter::Number(0));
+    }
+
+    #[test]
+    fn parameter_from_owned() {
+        let bytes = b"abc".to_vec();
+        let pointer = bytes.as_ptr();
+        match Parameter::from(bytes) {
+            Parameter::String(value) => assert_eq!(value.as_ptr(), pointer),
+            other => panic!("Unexpected parameter {other:?}"),
+        }
+        assert_eq!(
+            Parameter::from("abc".to_string()),
+            Parameter::String(b"abc".to_vec())
+        );
+    }
+
+    #[test]
+    fn parameter_equality() {
+        assert_eq!(Parameter::from(5), Parameter::Number(5));
+        assert_ne!(Parameter::from(5), Parameter::from(6));
+        assert_eq!(Parameter::from("abc"), Parameter::String(b"abc".to_vec()));
+        assert_ne!(Parameter::from("abc"), Parameter::from("abd"));
+        assert_ne!(Parameter::from(0), Parameter::from(""));
+    }
+
+    #[test]
+    fn parameter_from_str() {
+        assert_eq!("42".parse(), Ok(Parameter::Number(42)));
+        assert_eq!("-3".parse(), Ok(Parameter::Number(-3)));
+        assert_eq!("hello".parse(), Ok(Parameter::from("hello")));
+        assert_eq!("4x".parse(), Ok(Parameter::from("4x")));
+        assert_eq!("".parse(), Ok(Parameter::from("")));
+        assert_eq!("99999999999".parse(), Ok(Parameter::from("99999999999")));
+    }
+
+    #[test]
+    fn parameter_hash() {
+        let params = HashSet::from([Parameter::from(5), Parameter::from("abc")]);
+        assert!(params.contains(&Parameter::Number(5)));
+        assert!(params.contains(&Parameter::String(b"abc".to_vec())));
+        assert!(!params.contains(&Parameter::from("5")));
+    }
+
+    #[test]
+    fn parameter_display() {
+        assert_eq!(Parameter::from(-42).to_string(), "-42");
+        assert_eq!(Parameter::from("abc").to_string(), "abc");
+        assert_eq!(
+            Parameter::String(b"a\xffb".to_vec()).to_string(),
+            "a\u{fffd}b"
+        );
+        assert_eq!(Parameter::from(b"\xc3").to_string(), "\u{fffd}");
+    }
+
+    #[test]
+    fn parameter_debug() {
+        assert_eq!(format!("{:?}", Parameter::from(-3)), "Number(-3)");
+        assert_eq!(
+            format!("{:?}", Parameter::String(b"a\"b".to_vec())),
+            r#"String("a\"b")"#
+        );
+        assert_eq!(
+            format!("{:?}", Parameter::from(b"\xff\x01")),
+            "Str([255, 1])"
+        );
+    }
+
+    #[test]
+    fn missing_parameter_index() {
+        let mut expand_context = ExpandContext::new();
+        assert_eq!(
+            expand_context.expand(b"\x1b[%p", &[Parameter::from(1)]),
+            Err(Error::MissingParameterIndex)
+        );
+        assert_eq!(
+            expand_context.expand(b"%p1%d%p", &[Parameter::from(1)]),
+            Err(Error::MissingParameterIndex)
+        );
+    }
+
+    #[test]
+    fn sparse_parameters() {
+        let mut expand_context = ExpandContext::new();
+        let params = HashMap::from([(3, Parameter::from(42)), (10, Parameter::from(10))]);
+        assert_str(
+            expand_context.expand_sparse(b"%p1%d %p2%d %p3%d %p9%d", &params),
+            "0 0 42 0",
+        );
+    }
+
+    #[test]
+    fn delay_ignored() {
+        let mut expand_context = ExpandContext::new();
+        assert_str(
+            expand_context.expand(b"%p1%d$<5*/>%p1%d", &[Parameter::from(42)]),
+            "4242",
+        );
+    }
+
+    #[test]
+    fn max_output() {
+        let mut expand_context = ExpandContext::new();
+        assert_eq!(
+            expand_context
+                .expand(b"%{1}%60000d", &[])
+                .map(|output| output.len()),
+            Ok(60000)
+        );
+        assert_eq!(
+            expand_context.expand(b"%{1}%60000d%{1}%60000d", &[]),
+            Err(Error::OutputTooLarge)
+        );
+        let literal = vec![b'x'; DEFAULT_MAX_OUTPUT + 1];
+        assert_eq!(
+            expand_context.expand(&literal, &[]),
+            Err(Error::OutputTooLarge)
+        );
+        assert_eq!(
+            expand_context.expand(&literal[1..], &[]),
+            Ok(literal[1..].to_vec())
+        );
+
+        expand_context.set_max_output(None);
+        assert_eq!(
+            expand_context
+                .expand(b"%{1}%60000d%{1}%60000d", &[])
+                .map(|output| output.len()),
+            Ok(120_000)
+        );
+
+        expand_context.set_max_output(Some(1000));
+        assert_eq!(
+            expand_context.expand(b"%{1}%60000d", &[]),
+            Err(Error::OutputTooLarge)
+        );
+        assert_eq!(
+            expand_context
+                .expand(b"%{1}%1000d", &[])
+                .map(|output| output.len()),
+            Ok(1000)
+        );
+        assert_eq!(
+            expand_context.expand(b"x%{1}%1000d", &[]),
+            Err(Error::OutputTooLarge)
+        );
+        assert_eq!(
+            expand_context.expand(b"%{1}%.1001d", &[]),
+            Err(Error::OutputTooLarge)
+        );
+
+        // The limit applies to each expansion, not to the existing output
+        let mut output = vec![b'x'; 2000];
+        expand_context
+            .expand_into(b"%{1}%999d", &[], &mut output)
+            .unwrap();
+        assert_eq!(output.len(), 2999);
+
+        expand_context.set_baud_rate(Some(9600));
+        assert_eq!(
+            expand_context.expand(b"$<10000>", &[]),
+            Err(Error::OutputTooLarge)
+        );
+    }
+
+    #[test]
+    fn max_capability_len() {
+        let mut expand_context = ExpandContext::new();
+        let long_cap = vec![b'x'; 10000];
+        assert_eq!(expand_context.expand(&long_cap, &[]), Ok(long_cap.clone()));
+
+        expand_context.set_max_capability_len(Some(8));
+        assert_str(
+            expand_context.expand(b"%p1%5d", &[Parameter::from(1)]),
+            "    1",
+        );
+        assert_str(expand_context.expand(b"12345678", &[]), "12345678");
+        assert_eq!(
+            expand_context.expand(b"123456789", &[]),
+            Err(Error::CapabilityTooLong)
+        );
+        assert_eq!(
+            expand_context.expand(&long_cap, &[]),
+            Err(Error::CapabilityTooLong)
+        );
+
+        expand_context.set_max_capability_len(None);
+        assert_eq!(expand_context.expand(&long_cap, &[]), Ok(long_cap));
+    }
+
+    #[test]
+    fn delay_padding() {
+        let mut expand_context = ExpandContext::new();
+        expand_context.set_baud_rate(Some(9600));
+        assert_eq!(
+            expand_context.expand(b"a$<5>b", &[]),
+            Ok(b"a\0\0\0\0\0b".to_vec())
+        );
+        assert_eq!(expand_context.expand(b"$<2.5>", &[]), Ok(vec![0; 2]));
+        assert_eq!(expand_context.expand(b"$<0>", &[]), Ok(vec![]));
+
+        expand_context.set_pad_char(b'*');
+        expand_context.set_affected_lines(3);
+        assert_eq!(expand_context.expand(b"$<20*/>", &[]), Ok(vec![b'*'; 64]));
+        assert_eq!(expand_context.expand(b"$<20/>", &[]), Ok(vec![b'*'; 21]));
+
+        expand_context.set_xon_xoff(true);
+        assert_eq!(expand_context.expand(b"$<20*/>", &[]), Ok(vec![b'*'; 64]));
+        assert_eq!(expand_context.expand(b"x$<20*>", &[]), Ok(b"x".to_vec()));
+
+        expand_context.set_baud_rate(None);
+        assert_eq!(expand_context.expand(b"x$<20*/>", &[]), Ok(b"x".to_vec()));
+
+        // The output limit stops huge delays.
+        expand_context.set_baud_rate(Some(u32::MAX));
+        expand_context.set_affected_lines(u32::MAX);
+        assert_eq!(
+            expand_context.expand(b"$<4294967295*/>", &[]),
+            Err(Error::OutputTooLarge)
+        );
+    }
+
+    #[test]
+    fn delay_parsing() {
+        let delays = |cap: &[u8]| {
+            CompiledCap::compile(cap).map(|compiled| {
+                compiled
+                    .delays()
+                    .map(|delay| {
+                        (
+                            delay.tenths(),
+                            delay.is_proportional(),
+                            delay.is_mandatory(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(delays(b"\x1b[H$<5>"), Ok(vec![(50, false, false)]));
+        assert_eq!(
+            delays(b"$<5*>x$<10/>"),
+            Ok(vec![(50, true, false), (100, false, true)])
+        );
+        assert_eq!(delays(b"$<2.55*/>"), Ok(vec![(25, true, true)]));
+        assert_eq!(delays(b"$<.5/*>"), Ok(vec![(5, true, true)]));
+        assert_eq!(delays(b"$<0>"), Ok(vec![(0, false, false)]));
+        assert_eq!(delays(b"no delay"), Ok(vec![]));
+
+        for cap in [
+            b"$<>".as_slice(),
+            b"$<*>",
+            b"$<abc>",
+            b"$<5x>",
+            b"$<5*5>",
+            b"$<1.2.3>",
+            b"$<5",
+            b"$<",
+        ] {
+            assert_eq!(delays(cap), Err(Error::MalformedDelay));
+        }
+    }
+
+    #[test]
+    fn dollar_literal() {
+        let mut expand_context = ExpandContext::new();
+        assert_str(
+            expand_context.expand(b"$5 $$<1> %p1%d$", &[Parameter::from(7)]),
+            "$5 $ 7$",
+        );
+        assert_str(
+            expand_context.expand(b"$%p1%d", &[Parameter::from(7)]),
+            "$7",
+        );
+    }
+
+    #[test]
+    fn delay_padding_conditional() {
+        let mut expand_context = ExpandContext::new();
+        expand_context.set_baud_rate(Some(90000));
+        let cap = b"%?%p1%t$<1>%e$<2>%;!";
+        assert_eq!(
+            expand_context.expand(cap, &[Parameter::from(1)]),
+            Ok(b"\0\0\0\0\0\0\0\0\0\0!".to_vec())
+        );
+        assert_eq!(
+            expand_context.expand(cap, &[Parameter::from(0)]),
+            Ok([[0; 20].as_slice(), b"!"].concat())
+        );
+    }
+
+    #[test]
+    fn conditional_without_start() {
+        let mut expand_context = ExpandContext::new();
+        for (cap, param, expected) in [
+            (b"%p1%tyes%;!".as_slice(), 1, "yes!"),
+            (b"%p1%tyes%;!", 0, "!"),
+            (b"%p1%tyes%eno%;!", 1, "yes!"),
+            (b"%p1%tyes%eno%;!", 0, "no!"),
+            (b"%p1%tyes", 0, ""),
+            (b"%p1%{1}%=%tone%e%p1%{2}%=%ttwo%eother%;", 2, "two"),
+            (b"%p1%{1}%=%tone%e%p1%{2}%=%ttwo%eother%;", 3, "other"),
+        ] {
+            assert_str(
+                expand_context.expand(cap, &[Parameter::from(param)]),
+                expected,
+            );
+            let cap_with_start = [b"%?", cap].concat();
+            assert_str(
+                expand_context.expand(&cap_with_start, &[Parameter::from(param)]),
+                expected,
+            );
         }
-        Parameter::String(mut s) => match op {
-            's' => {
-                if let Some(precision) = flags.precision
-                    && let precision = usize::from(precision)
-                    && precision < s.len()
-                {
-                    s.truncate(precision);
-                }
-                s
-            }
-            _ => return Err(Error::FormatTypeMismatch),
-        },
-    };
-    if usize::from(flags.width) > s.len() {
-        let n = usize::from(flags.width) - s.len();
-        if flags.left {
-            s.extend(repeat_n(b' ', n));
-        } else {
-            let mut s_ = Vec::with_capacity(usize::from(flags.width));
-            s_.extend(repeat_n(b' ', n));
-            s_.extend(s);
-            s = s_;
+    }
+
+    #[test]
+    fn strict_conditionals() {
+        let mut expand_context = ExpandContext::new();
+        let unterminated = [
+            b"%?%p1%tyes".as_slice(),
+            b"%?%p1%tyes%eno",
+            b"%?%p1%t%?%p2%tyes%;",
+            b"%p1%tyes",
+            b"%?%p1%tyes%;%eno",
+        ];
+        for cap in unterminated {
+            assert!(expand_context.expand(cap, &[Parameter::from(1)]).is_ok());
+        }
+
+        expand_context.set_strict_conditionals(true);
+        for cap in unterminated {
+            assert_eq!(
+                expand_context.expand(cap, &[Parameter::from(1)]),
+                Err(Error::UnterminatedConditional),
+                "{}",
+                cap.escape_ascii()
+            );
+        }
+        let mut stream = expand_context.expand_stream(b"x%?%p1%ty", &[]);
+        assert_eq!(stream.next(), Some(Err(Error::UnterminatedConditional)));
+        drop(stream);
+        for cap in [
+            b"%?%p1%tyes%eno%;".as_slice(),
+            b"%p1%tyes%;",
+            b"%?%p1%t%?%p2%tyes%;%;",
+            b"no conditional",
+        ] {
+            assert!(expand_context.expand(cap, &[Parameter::from(1)]).is_ok());
         }
     }
-    Ok(s)
-}
 
-impl Default for ExpandContext {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn binary_mixed_types() {
+        let mut expand_context = ExpandContext::new();
+        let params = [Parameter::from("abc"), Parameter::from(5)];
+        for op in "+-*/m|&^=<>AO".chars() {
+            for operands in ["%p1%p2", "%p2%p1", "%p1%p1"] {
+                let cap = format!("{operands}%{op}");
+                assert_eq!(
+                    expand_context.expand(cap.as_bytes(), &params),
+                    Err(Error::TypeMismatch(op)),
+                    "{cap}"
+                );
+            }
+            let cap = format!("%p1%{op}");
+            assert_eq!(
+                expand_context.expand(cap.as_bytes(), &params),
+                Err(Error::StackUnderflow(op)),
+                "{cap}"
+            );
+        }
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::{Error, ExpandContext, Parameter};
+    #[test]
+    fn param_usage() {
+        let usage = required_params(b"\x1b[%i%p1%d;%p2%dH").unwrap();
+        assert_eq!(usage.count(), 2);
+        assert_eq!(usage.param_type(1), Some(ParamType::Number));
+        assert_eq!(usage.param_type(2), Some(ParamType::Number));
+        assert_eq!(usage.param_type(3), None);
 
-    /// Compare the result of `expand()` to the expected string
-    fn assert_str(actual: Result<Vec<u8>, Error>, expected: &str) {
-        assert_eq!(str::from_utf8(&actual.unwrap()).unwrap(), expected);
+        let usage =
+            required_params(b"\x1b[%?%p1%{8}%<%t3%p1%d%e%p1%{16}%<%t9%p1%{8}%-%d%e38;5;%p1%d%;m")
+                .unwrap();
+        assert_eq!(usage.count(), 1);
+        assert_eq!(usage.param_type(1), Some(ParamType::Number));
+
+        let usage = required_params(b"\x1b]2;%p3%s\x07%p5%l%d%p2%Pa").unwrap();
+        assert_eq!(usage.count(), 5);
+        assert_eq!(usage.param_type(1), None);
+        assert_eq!(usage.param_type(2), None);
+        assert_eq!(usage.param_type(3), Some(ParamType::String));
+        assert_eq!(usage.param_type(5), Some(ParamType::String));
+
+        let usage = required_params(b"%p1%s%p1%d").unwrap();
+        assert_eq!(usage.param_type(1), None);
+        assert_eq!(usage.param_type(0), None);
+
+        assert_eq!(required_params(b"\x1b[H").unwrap().count(), 0);
+        assert_eq!(required_params(b"%p"), Err(Error::MissingParameterIndex));
     }
 
     #[test]
-    fn multiple_parameters() {
-        let mut expand_context = ExpandContext::default();
-        assert_str(
-            expand_context.expand(
-                b"%p1%p2%p3%p4%p5%p6%p7%p8%p9%d%d%d%d%d%s%s%s%d",
-                &[
-                    Parameter::from(1),
-                    Parameter::from(b"Two"),
-                    Parameter::from(b"Three".as_slice()),
-                    Parameter::from("Four"),
-                    Parameter::from(5),
-                    Parameter::from(6),
-                    Parameter::from(7),
-                    Parameter::from(8),
-                    Parameter::from(9),
-                ],
+    fn expand_termcap() {
+        let expand_context = ExpandContext::new();
+        for (cap, params, expected) in [
+            (
+                b"\x1b[%i%d;%dH".as_slice(),
+                [4, 9].as_slice(),
+                b"\x1b[5;10H".as_slice(),
             ),
-            "98765FourThreeTwo1",
+            (b"\x1b=%+ %+ ", &[2, 10], b"\x1b=\"*"),
+            (b"%r%d;%d", &[1, 2], b"2;1"),
+            (b"%2:%3:%2", &[5, 7, 123], b"05:007:123"),
+            (b"%>A!%d,%>A!%d", &[70, 60], b"103,60"),
+            (b"%n%.%.", &[2, 10], b"bj"),
+            (b"%B%d,%D%d", &[42, 42], b"66,22"),
+            (b"%d%d%d", &[7], b"700"),
+            (b"100%%", &[], b"100%"),
+        ] {
+            assert_eq!(
+                expand_context.expand_termcap(cap, params).as_deref(),
+                Ok(expected),
+                "{}",
+                cap.escape_ascii()
+            );
+        }
+        assert_eq!(
+            expand_context.expand_termcap(b"%p1%d", &[1]),
+            Err(Error::UnrecognizedFormatOption('p'))
+        );
+        assert_eq!(
+            expand_context.expand_termcap(b"%>A", &[1]),
+            Err(Error::MalformedCharacterConstant)
+        );
+        assert_eq!(
+            expand_context.expand_termcap(b"%i", &[i32::MAX]),
+            Err(Error::ArithmeticOverflow('i'))
+        );
+        assert_eq!(
+            expand_context.expand_termcap(b"%B%d", &[-1_342_177_289]),
+            Err(Error::ArithmeticOverflow('B'))
+        );
+        assert_eq!(
+            expand_context.expand_termcap(b"%d%", &[1]),
+            Err(Error::UnrecognizedFormatOption('%'))
         );
     }
 
     #[test]
-    fn delay_ignored() {
+    fn analyze_cap() {
+        let info = analyze(b"\x1b[%i%p1%d;%p2%dH").unwrap();
+        assert_eq!(info.params().count(), 2);
+        assert!(info.uses_increment());
+        assert!(!info.uses_conditionals());
+        assert!(!info.uses_delays());
+        assert_eq!(info.variables(), VarTrace::default());
+
+        let info = analyze(b"%?%p1%t%gA%{1}%+%PA%e%p2%Pb%;$<5>").unwrap();
+        assert_eq!(info.params().count(), 2);
+        assert!(!info.uses_increment());
+        assert!(info.uses_conditionals());
+        assert!(info.uses_delays());
+        assert_eq!(info.variables().read().collect::<String>(), "A");
+        assert_eq!(info.variables().written().collect::<String>(), "Ab");
+
+        assert_eq!(analyze(b"%p0"), Err(Error::InvalidParameterIndex('0')));
+    }
+
+    #[test]
+    fn string_parameter_not_copied() {
+        let params = [Parameter::from(4), Parameter::String(b"text".to_vec())];
+        let mut provider = slice_provider(&params);
+        let Parameter::String(original) = &params[1] else {
+            unreachable!();
+        };
+        let Parameter::Str(borrowed) = provider(2) else {
+            panic!("string parameter is not borrowed");
+        };
+        assert!(std::ptr::eq(borrowed, original.as_slice()));
+        assert_eq!(provider(1), Parameter::Number(4));
+
+        let mut expand_context = ExpandContext::new();
+        assert_str(expand_context.expand(b"%p1%d;%p2%l%d", &params), "4;4");
+    }
+
+    #[test]
+    fn expand_iter() {
         let mut expand_context = ExpandContext::new();
+        let cap = b"\x1b[%i%p1%d;%p2%dH%p3%s";
+        let params = vec![Parameter::from(4), Parameter::from(9), Parameter::from("!")];
+        assert_str(expand_context.expand_iter(cap, params), "\x1b[5;10H!");
         assert_str(
-            expand_context.expand(b"%p1%d$<5*/>%p1%d", &[Parameter::from(42)]),
-            "4242",
+            expand_context.expand_iter(b"%p1%d;%p2%d;%p3%d", [1, 2].map(Parameter::from)),
+            "1;2;0",
+        );
+        assert_str(
+            expand_context.expand_iter(b"%p1%d%p9%d", (1..20).map(Parameter::from)),
+            "19",
+        );
+    }
+
+    #[test]
+    fn expand_stream() {
+        let mut expand_context = ExpandContext::new();
+        let params = [Parameter::from(4), Parameter::from("ab")];
+        let output: Result<Vec<u8>, Error> = expand_context
+            .expand_stream(b"x%p1%d%p2%s$<1000>y", &params)
+            .collect();
+        assert_eq!(output, Ok(b"x4aby".to_vec()));
+
+        expand_context.set_baud_rate(Some(9600));
+        let output: Result<Vec<u8>, Error> = expand_context
+            .expand_stream(b"x$<1000>y", &params)
+            .collect();
+        assert_eq!(
+            output,
+            Ok([b"x".to_vec(), vec![0; 1066], b"y".to_vec()].concat())
         );
+
+        let mut stream = expand_context.expand_stream(b"ab%p1%s", &params);
+        assert_eq!(stream.next(), Some(Ok(b'a')));
+        assert_eq!(stream.next(), Some(Ok(b'b')));
+        assert_eq!(stream.next(), Some(Err(Error::FormatTypeMismatch)));
+        assert_eq!(stream.next(), None);
+        drop(stream);
+
+        let mut stream = expand_context.expand_stream(b"%d", &params);
+        assert_eq!(stream.next(), Some(Err(Error::StackUnderflow('d'))));
+        assert_eq!(stream.next(), None);
+        drop(stream);
+
+        expand_context.set_max_output(Some(100));
+        let output: Result<Vec<u8>, Error> = expand_context
+            .expand_stream(b"x$<1000>y", &params)
+            .collect();
+        assert_eq!(output, Err(Error::OutputTooLarge));
+    }
+
+    #[test]
+    fn expand_with_provider() {
+        let mut expand_context = ExpandContext::new();
+        let cap = b"%?%p1%t%p2%d%e%p3%d%;";
+        for (first, expected, expected_calls) in [(1, "2", [1, 2]), (0, "3", [1, 3])] {
+            let mut calls = vec![];
+            let output = expand_context.expand_with(cap, |index| {
+                calls.push(index);
+                Parameter::from(if index == 1 { first } else { index as i32 })
+            });
+            assert_str(output, expected);
+            assert_eq!(calls, expected_calls);
+        }
+
+        let mut calls = vec![];
+        let output = expand_context.expand_with(b"%i%p1%d%p2%d%p1%d", |index| {
+            calls.push(index);
+            Parameter::from(index as i32)
+        });
+        assert_str(output, "232");
+        assert_eq!(calls, [1, 2]);
+    }
+
+    #[test]
+    fn validate() {
+        for cap in [
+            b"\x1b[%i%p1%d;%p2%dH".as_slice(),
+            b"%?%p1%t;1%;%?%p2%t;4%;m",
+            b"%p1%?%p2%t%d%e%s%;",
+            b"%?%p1%{8}%<%t3%p1%d%e%p1%{16}%<%t9%p1%{8}%-%d%;",
+            b"%p1%PA%gA%d$<5>",
+            b"plain text",
+        ] {
+            assert_eq!(ExpandContext::validate(cap), Ok(()), "{cap:?}");
+        }
+        for (cap, offset, error) in [
+            (b"ab%Y".as_slice(), 2, Error::UnrecognizedFormatOption('Y')),
+            (b"%p1%d%p", 5, Error::MissingParameterIndex),
+            (b"x$<5", 1, Error::MalformedDelay),
+            (b"%p1%d%;", 5, Error::UnbalancedConditional),
+            (b"%?%p1%tyes", 0, Error::UnbalancedConditional),
+            (b"%p1%P!", 3, Error::InvalidVariableName('!')),
+            (b"%p1%+", 3, Error::StackUnderflow('+')),
+            (b"%?%p1%t%p2%e%;%d", 14, Error::StackUnderflow('d')),
+            (b"%d", 0, Error::StackUnderflow('d')),
+        ] {
+            assert_eq!(
+                ExpandContext::validate(cap),
+                Err(Error::Invalid {
+                    offset,
+                    error: Box::new(error)
+                }),
+                "{cap:?}"
+            );
+        }
     }
 
     #[test]
@@ -661,6 +2966,51 @@ mod test {
             ),
             Ok(vec![42, 128, 1]),
         );
+        expand_context.set_nul_as_0x80(false);
+        assert_eq!(
+            expand_context.expand(b"%p1%c", &[Parameter::from(0)]),
+            Ok(vec![0])
+        );
+    }
+
+    #[test]
+    fn builder() {
+        let mut expand_context = ExpandContext::builder().build();
+        assert_eq!(
+            expand_context.expand(b"a$<100>%p1%c", &[]),
+            Ok(b"a\x80".to_vec())
+        );
+
+        let mut expand_context = ExpandContext::builder()
+            .baud_rate(Some(9600))
+            .pad_char(b'*')
+            .affected_lines(2)
+            .xon_xoff(false)
+            .output_limit(Some(1000))
+            .max_capability_len(Some(20))
+            .strict_types(false)
+            .strict_conditionals(true)
+            .nul_as_0x80(false)
+            .build();
+        assert_eq!(
+            expand_context.expand(
+                b"a$<10*>%p1%c%p2%d",
+                &[Parameter::from(0), Parameter::from("7")]
+            ),
+            Ok([b"a".as_slice(), &[b'*'; 21], b"\0", b"7"].concat())
+        );
+        assert_eq!(
+            expand_context.expand(b"%?%p1%t", &[]),
+            Err(Error::UnterminatedConditional)
+        );
+        assert_eq!(
+            expand_context.expand(&[b'x'; 21], &[]),
+            Err(Error::CapabilityTooLong)
+        );
+        assert_eq!(
+            expand_context.expand(b"$<1000>", &[]),
+            Err(Error::OutputTooLarge)
+        );
     }
 
     #[test]
@@ -754,6 +3104,28 @@ mod test {
         assert_str(expand_context.expand(b"%gA%d%gZ%d%ga%d%gz%d", &[]), "1200");
     }
 
+    #[test]
+    fn variable_trace() {
+        let mut expand_context = ExpandContext::new();
+        let (output, trace) = expand_context
+            .expand_traced(b"%p1%PA%gA%d", &[Parameter::from(42)])
+            .unwrap();
+        assert_eq!(output, b"42");
+        assert!(trace.was_written('A'));
+        assert!(trace.was_read('A'));
+        assert_eq!(trace.read().collect::<String>(), "A");
+        assert_eq!(trace.written().collect::<String>(), "A");
+
+        let (_, trace) = expand_context.expand_traced(b"%gB%Pz%gA%Pa", &[]).unwrap();
+        assert_eq!(trace.read().collect::<String>(), "AB");
+        assert_eq!(trace.written().collect::<String>(), "az");
+        assert!(!trace.was_read('a'));
+        assert!(!trace.was_written('B'));
+
+        let (_, trace) = expand_context.expand_traced(b"%p1%d", &[]).unwrap();
+        assert_eq!(trace, VarTrace::default());
+    }
+
     #[test]
     fn variable_bad_name() {
         let mut expand_context = ExpandContext::new();
@@ -780,6 +3152,32 @@ mod test {
             expand_context.expand(b"%'ab'", &[]),
             Err(Error::MalformedCharacterConstant)
         );
+        assert_eq!(
+            expand_context.expand(b"%'\\q'%d", &[]),
+            Err(Error::MalformedCharacterConstant)
+        );
+        assert_eq!(
+            expand_context.expand(b"%'\\nn'%d", &[]),
+            Err(Error::MalformedCharacterConstant)
+        );
+    }
+
+    #[test]
+    fn escaped_char_constants() {
+        let mut expand_context = ExpandContext::new();
+        for (cap, value) in [
+            (b"%'\\n'%d", 10),
+            (b"%'\\r'%d", 13),
+            (b"%'\\t'%d", 9),
+            (b"%'\\b'%d", 8),
+            (b"%'\\f'%d", 12),
+            (b"%'\\s'%d", 32),
+            (b"%'\\0'%d", 0),
+            (b"%'\\\\'%d", 92),
+            (b"%'\\''%d", 39),
+        ] {
+            assert_str(expand_context.expand(cap, &[]), &value.to_string());
+        }
     }
 
     #[test]
@@ -807,6 +3205,11 @@ mod test {
             expand_context.expand(b"%p1%l%d", &[Parameter::from("Hello, World!")]),
             "13",
         );
+        // The length is in bytes like in ncurses, not in characters
+        assert_str(
+            expand_context.expand(b"%p1%l%d", &[Parameter::from("Grüße, 世界")]),
+            "15",
+        );
     }
 
     #[test]
@@ -848,6 +3251,46 @@ mod test {
         }
     }
 
+    #[test]
+    fn arithmetic_overflow() {
+        let tests = [
+            (i32::MAX, '+', 1),
+            (i32::MIN, '-', 1),
+            (i32::MAX, '*', 2),
+            (i32::MIN, '/', -1),
+            (i32::MIN, 'm', -1),
+        ];
+        let mut expand_context = ExpandContext::new();
+        for (operand1, operation, operand2) in tests {
+            let cap = format!("%p1%p2%{operation}%d");
+            assert_eq!(
+                expand_context.expand(
+                    cap.as_bytes(),
+                    &[Parameter::from(operand1), Parameter::from(operand2)],
+                ),
+                Err(Error::ArithmeticOverflow(operation)),
+                "Failed for %{operation}"
+            );
+        }
+        assert_eq!(
+            expand_context.expand(b"%{2147483647}%{1}%+", &[]),
+            Err(Error::ArithmeticOverflow('+'))
+        );
+        assert_eq!(
+            expand_context.expand(b"%i", &[Parameter::from(i32::MAX)]),
+            Err(Error::ArithmeticOverflow('i'))
+        );
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let mut expand_context = ExpandContext::new();
+        assert_str(
+            expand_context.expand(b"%p1%p2%/%d %p1%p2%m%d", &[Parameter::from(7)]),
+            "0 0",
+        );
+    }
+
     #[test]
     fn negation() {
         let mut expand_context = ExpandContext::new();
@@ -860,6 +3303,72 @@ mod test {
         );
     }
 
+    #[test]
+    fn legacy_unary_operators() {
+        let mut expand_context = ExpandContext::new();
+        for (value, bcd, reverse) in [
+            (0, "0", "0"),
+            (9, "9", "-9"),
+            (10, "16", "-10"),
+            (42, "66", "22"),
+            (99, "153", "93"),
+            (123, "195", "101"),
+        ] {
+            assert_str(
+                expand_context.expand(b"%p1%B%d", &[Parameter::from(value)]),
+                bcd,
+            );
+            assert_str(
+                expand_context.expand(b"%p1%D%d", &[Parameter::from(value)]),
+                reverse,
+            );
+        }
+        assert_str(expand_context.expand(b"%{24}%B%c", &[]), "$");
+        for value in [i32::MAX, -1_342_177_289] {
+            assert_eq!(
+                expand_context.expand(b"%p1%B%d", &[Parameter::from(value)]),
+                Err(Error::ArithmeticOverflow('B'))
+            );
+        }
+        assert_eq!(
+            expand_context.expand(b"%B", &[]),
+            Err(Error::StackUnderflow('B'))
+        );
+    }
+
+    #[test]
+    fn logical_truthiness() {
+        let mut expand_context = ExpandContext::new();
+        for (x, y, expected) in [
+            (5, 3, "1 1 0 1"),
+            (5, -3, "1 1 0 1"),
+            (-5, -3, "1 1 0 1"),
+            (-5, 0, "0 1 0 1"),
+            (0, 0, "0 0 1 0"),
+        ] {
+            assert_str(
+                expand_context.expand(
+                    b"%p1%p2%A%d %p1%p2%O%d %p1%!%d %?%p1%t1%e0%;",
+                    &[Parameter::from(x), Parameter::from(y)],
+                ),
+                expected,
+            );
+        }
+        // Comparisons chained into logical operators
+        let cap = b"%p1%{0}%>%p2%{0}%<%A%d %p1%{0}%=%p2%{0}%=%O%d";
+        for (x, y, expected) in [
+            (1, -1, "1 0"),
+            (-1, -1, "0 0"),
+            (0, 2, "0 1"),
+            (7, 0, "0 1"),
+        ] {
+            assert_str(
+                expand_context.expand(cap, &[Parameter::from(x), Parameter::from(y)]),
+                expected,
+            );
+        }
+    }
+
     #[test]
     fn increment() {
         let mut expand_context = ExpandContext::new();
@@ -874,6 +3383,34 @@ mod test {
             ),
             "11_16_20_11_16_20",
         );
+        let params = [Parameter::from(4), Parameter::from(9)];
+        for _ in 0..2 {
+            assert_str(expand_context.expand(b"%i%i%p1%d;%p2%d", &params), "5;10");
+        }
+        assert_eq!(params, [Parameter::from(4), Parameter::from(9)]);
+    }
+
+    #[test]
+    fn xor_params() {
+        let mut expand_context = ExpandContext::new();
+        // Cursor addressing of the Datamedia 2500, `cup` in its terminfo entry
+        let params = [2, 10, 3].map(Parameter::from);
+        assert_eq!(
+            expand_context.expand(b"\x0c%n%p2%c%p1%c", &params),
+            expand_context.expand(b"\x0c%p2%{96}%^%c%p1%{96}%^%c", &params),
+        );
+        assert_str(
+            expand_context.expand(b"\x0c%n%p2%c%p1%c", &params),
+            "\x0cjb",
+        );
+        assert_str(
+            expand_context.expand(b"%n%p1%d,%p2%d,%p3%d,%n%p1%d", &params),
+            "98,106,3,2",
+        );
+        assert_eq!(
+            expand_context.expand(b"%n", &[Parameter::from("x")]),
+            Err(Error::TypeMismatch('n'))
+        );
     }
 
     #[test]
@@ -1044,6 +3581,35 @@ mod test {
         }
     }
 
+    #[test]
+    fn format_colon_equivalent() {
+        let params = [
+            Parameter::from(-42),
+            Parameter::from(0),
+            Parameter::from(42),
+            Parameter::from("One"),
+            Parameter::from(""),
+        ];
+        let mut expand_context = ExpandContext::new();
+        for param in params {
+            let ops = if matches!(param, Parameter::Number(_)) {
+                ["d", "o", "x", "X", "#x", " d", "5d", ".3d"].as_slice()
+            } else {
+                ["s", "5s", ".2s"].as_slice()
+            };
+            for op in ops {
+                let bare = format!("%p1%{op}");
+                let colon = format!("%p1%:{op}");
+                let params = [param.clone()];
+                assert_eq!(
+                    expand_context.expand(bare.as_bytes(), &params),
+                    expand_context.expand(colon.as_bytes(), &params),
+                    "{bare} {param:?}"
+                );
+            }
+        }
+    }
+
     #[test]
     fn format_width_overflow() {
         let mut expand_context = ExpandContext::new();
@@ -1075,6 +3641,33 @@ mod test {
         );
     }
 
+    #[test]
+    fn lenient_coercion() {
+        let mut expand_context = ExpandContext::new();
+        expand_context.set_lenient(true);
+        for (cap, param, expected) in [
+            (b"%p1%s".as_slice(), Parameter::from(63), "63"),
+            (b"%p1%3d", Parameter::from("12"), " 12"),
+            (b"%p1%3d", Parameter::from("one"), "  0"),
+            (b"%p1%{1}%+%d", Parameter::from(" 41 "), "42"),
+            (b"%p1%!%d", Parameter::from("0"), "1"),
+            (b"%p1%l%d", Parameter::from(-123), "4"),
+            (b"%p1%l%d", Parameter::from(0), "1"),
+            (b"%p1%c", Parameter::from("65"), "A"),
+            (b"%?%p1%tyes%eno%;", Parameter::from("1"), "yes"),
+            (b"%?%p1%tyes%eno%;", Parameter::from("x"), "no"),
+            (b"%i%p1%d", Parameter::from("5"), "6"),
+        ] {
+            assert_str(expand_context.expand(cap, &[param]), expected);
+        }
+
+        expand_context.set_lenient(false);
+        assert_eq!(
+            expand_context.expand(b"%p1%{1}%+%d", &[Parameter::from("41")]),
+            Err(Error::TypeMismatch('+'))
+        );
+    }
+
     #[test]
     fn unrecornized_format_option() {
         let mut expand_context = ExpandContext::new();
@@ -19,8 +19,8 @@
 //! * Full support for extended capabilities
 //! * MIT + Apache 2.0 license (no obscene or obscure licenses)
 //! * Extensive unit test coverage
-//! * Minimal dependencies (`thiserror` only)
-//! * Lean code - no termcap, no Windows console, no unrelated stuff
+//! * Minimal dependencies (`thiserror` only, `memmap2` with the `mmap` feature)
+//! * Lean code - no termcap database, no Windows console, no unrelated stuff
 //! * UTF-8 is only used for capability names
 //! * 8-bit clean - string capabilities are byte slices
 //! * Minimal memory allocations
@@ -30,6 +30,12 @@
 //! The capability expansion code is based on the `term` crate with
 //! significant changes.
 
+mod error;
 pub mod expand;
 pub mod locate;
 pub mod parse;
+pub mod prelude;
+pub mod terminal;
+pub mod write;
+
+pub use error::Error;
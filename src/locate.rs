@@ -9,11 +9,15 @@
 //! Search for terminfo database file for the terminal
 
 use std::{
+    collections::HashSet,
     env,
-    ffi::OsStr,
+    ffi::{OsStr, OsString},
+    fs, io,
     path::{Path, PathBuf},
 };
 
+use crate::parse::{TerminfoOwned, parse};
+
 const TERMINFO_DIRS: &[&str] = &[
     "/etc/terminfo",
     "/lib/terminfo",
@@ -32,9 +36,34 @@ pub enum Error {
     /// Terminfo file for the terminal could not be found
     #[error("File not found")]
     FileNotFound,
+    /// Terminfo file could not be written
+    #[error("I/O error: {0}")]
+    IO(io::ErrorKind),
+}
+
+/// Check that the terminal name cannot escape the terminfo directory
+fn check_term_name(term_name: &OsStr) -> Result<(), Error> {
+    let bytes = term_name.as_encoded_bytes();
+    if bytes.is_empty()
+        || bytes == b"."
+        || bytes == b".."
+        || bytes.iter().any(|c| matches!(c, b'/' | b'\\' | b'\0'))
+    {
+        return Err(Error::InvalidTerminalName);
+    }
+    Ok(())
+}
+
+/// Returns the default directories searched for terminfo files
+///
+/// These directories are compiled in and searched last by `search_directories()`.
+#[must_use]
+pub const fn default_directories() -> &'static [&'static str] {
+    TERMINFO_DIRS
 }
 
 fn find_in_directory(term_name: &OsStr, dir: &Path) -> Result<PathBuf, Error> {
+    check_term_name(term_name)?;
     let Some(first_byte) = term_name.as_encoded_bytes().first() else {
         return Err(Error::InvalidTerminalName);
     };
@@ -60,6 +89,18 @@ fn find_in_directory(term_name: &OsStr, dir: &Path) -> Result<PathBuf, Error> {
 /// Returns all directories that are searched for terminfo files
 ///
 /// This function does not attempt to verify if the directories to be searched actually exist.
+/// Duplicate directories are removed, keeping the first occurrence.
+///
+/// The directories from the `TERMINFO` environment variable, `~/.terminfo` and
+/// the `TERMINFO_DIRS` environment variable are followed by the directories
+/// returned by `default_directories()`. An empty element in `TERMINFO_DIRS`
+/// inserts the default directories in its place.
+///
+/// The home directory is taken from `std::env::home_dir()`, which uses the
+/// passwd database if `HOME` is not set.
+///
+/// On Windows, `terminfo` in `%LOCALAPPDATA%` and `%APPDATA%` is searched
+/// after `~/.terminfo`.
 ///
 /// Returns a vector of directories.
 pub fn search_directories() -> Vec<PathBuf> {
@@ -73,12 +114,23 @@ pub fn search_directories() -> Vec<PathBuf> {
         search_dirs.push(PathBuf::from(&dir));
     }
 
-    // Search `.terminfo` in the home directory.
+    // Search `.terminfo` in the home directory. On Unix, `home_dir()` falls
+    // back to the passwd database if `HOME` is unset or empty. Nothing is
+    // searched if the home directory cannot be determined.
     if let Some(home_dir) = env::home_dir() {
         let dir = home_dir.join(".terminfo");
         search_dirs.push(dir);
     }
 
+    // Search `terminfo` in the Windows application data directories, used by
+    // ncurses ports such as MSYS2.
+    #[cfg(windows)]
+    for var in ["LOCALAPPDATA", "APPDATA"] {
+        if let Some(dir) = env::var_os(var).filter(|dir| !dir.is_empty()) {
+            search_dirs.push(PathBuf::from(dir).join("terminfo"));
+        }
+    }
+
     // Search colon separated directories from the `TERMINFO_DIRS`
     // environment variable.
     if let Ok(dirs) = env::var("TERMINFO_DIRS") {
@@ -95,11 +147,19 @@ pub fn search_directories() -> Vec<PathBuf> {
     // Search default terminfo locations (nothing is added if used already).
     search_dirs.extend(&mut default_dirs);
 
+    // Remove duplicates, keeping the first occurrence. Paths are compared by
+    // components, so that redundant separators don't matter.
+    let mut seen = HashSet::new();
+    search_dirs.retain(|dir| seen.insert(dir.clone()));
+
     search_dirs
 }
 
 /// Find terminfo database file for the terminal name
 ///
+/// Names that are empty, `.`, `..` or contain path separators or NUL are
+/// rejected, so that an untrusted `TERM` cannot escape the terminfo directories.
+///
 /// # Arguments
 ///
 /// * `term_name` - terminal name.
@@ -117,6 +177,104 @@ pub fn locate(term_name: impl AsRef<OsStr>) -> Result<PathBuf, Error> {
     Err(Error::FileNotFound)
 }
 
+/// Iterate over terminfo database files in all searched directories
+///
+/// The directories returned by `search_directories()` are scanned in order,
+/// yielding the terminal name and the file path for every file in the leaf
+/// directories, regardless of the leaf directory naming (first character or
+/// its hexadecimal code). Directories that cannot be read are skipped.
+///
+/// The same terminal name can appear more than once if it's found in several
+/// directories. `locate()` would find the first one.
+pub fn iter_all_terminals() -> impl Iterator<Item = (OsString, PathBuf)> {
+    fn read_dir(dir: &Path) -> impl Iterator<Item = fs::DirEntry> + use<> {
+        fs::read_dir(dir).into_iter().flatten().flatten()
+    }
+
+    search_directories()
+        .into_iter()
+        .flat_map(|dir| read_dir(&dir))
+        .flat_map(|leaf| read_dir(&leaf.path()))
+        .filter(|entry| entry.path().is_file())
+        .map(|entry| (entry.file_name(), entry.path()))
+}
+
+/// Load terminfo entry for the terminal from the `TERM` environment variable
+///
+/// The terminfo database is located, read and parsed.
+///
+/// Returns `Error::Environment` if `TERM` is unset and `Error::Locate` with
+/// `InvalidTerminalName` if it's empty, which is distinct from `FileNotFound`.
+pub fn load_current() -> Result<TerminfoOwned, crate::Error> {
+    let term_name = env::var("TERM")?;
+    let terminfo_file = locate(term_name)?;
+    let terminfo_buffer = fs::read(terminfo_file)?;
+    let terminfo = parse(&terminfo_buffer)?;
+    Ok(terminfo.into_owned())
+}
+
+impl TerminfoOwned {
+    /// Load terminfo entry for the current terminal
+    ///
+    /// Same as `load_current()`.
+    pub fn from_env() -> Result<Self, crate::Error> {
+        load_current()
+    }
+}
+
+/// Find terminfo database file for the terminal name or its more generic variant
+///
+/// If the database for the terminal name is not found, the last dash-separated
+/// component is removed from the name and the search is repeated. For example,
+/// `xterm-256color-italic` falls back to `xterm-256color` and then to `xterm`.
+///
+/// # Arguments
+///
+/// * `term_name` - terminal name.
+///
+/// Returns the file path and the terminal name it was found for, an error
+/// if no file is found.
+pub fn locate_with_fallback(term_name: &str) -> Result<(PathBuf, String), Error> {
+    let mut name = term_name;
+    loop {
+        match locate(name) {
+            Ok(file) => return Ok((file, name.to_string())),
+            Err(Error::FileNotFound) => {}
+            Err(err) => return Err(err),
+        }
+        match name.rsplit_once('-') {
+            Some((prefix, _)) if !prefix.is_empty() => name = prefix,
+            _ => return Err(Error::FileNotFound),
+        }
+    }
+}
+
+/// Install terminfo database file for the terminal name
+///
+/// The file is written to the leaf directory named after the first byte of the
+/// terminal name, like `locate()` expects it, which is created if needed. An existing file is
+/// overwritten.
+///
+/// # Arguments
+///
+/// * `term_name` - terminal name.
+/// * `bytes` - compiled terminfo entry.
+/// * `dir` - terminfo directory.
+///
+/// Returns the path of the written file.
+pub fn install(term_name: &str, bytes: &[u8], dir: &Path) -> Result<PathBuf, Error> {
+    check_term_name(OsStr::new(term_name))?;
+    let Some(&first_byte) = term_name.as_bytes().first() else {
+        return Err(Error::InvalidTerminalName);
+    };
+
+    let leaf_dir = dir.join(char::from(first_byte).to_string());
+    fs::create_dir_all(&leaf_dir).map_err(|err| Error::IO(err.kind()))?;
+    let filename = leaf_dir.join(term_name);
+    fs::write(&filename, bytes).map_err(|err| Error::IO(err.kind()))?;
+    Ok(filename)
+}
+
 #[cfg(test)]
 mod test {
     use std::fs::{File, create_dir, exists};
@@ -132,6 +290,17 @@ mod test {
         assert_eq!(locate(""), Err(Error::InvalidTerminalName));
     }
 
+    #[test]
+    fn path_traversal() {
+        for name in ["../evil", "..", ".", "a/b", "/etc/passwd", "a\\b", "a\0b"] {
+            assert_eq!(locate(name), Err(Error::InvalidTerminalName));
+        }
+        assert_eq!(
+            locate_with_fallback("../evil-xterm"),
+            Err(Error::InvalidTerminalName)
+        );
+    }
+
     #[test]
     fn missing_file() {
         // Not using TERM_NAME to avoid race conditions - `temp_env::with_vars`
@@ -182,6 +351,148 @@ mod test {
         );
     }
 
+    #[test]
+    fn fallback_name() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir = temp_dir.path();
+        let leaf_dir = temp_dir.join("n");
+        let terminfo_file = leaf_dir.join("no-such-terminal");
+        create_dir(leaf_dir).unwrap();
+        File::create(&terminfo_file).unwrap();
+
+        temp_env::with_vars(
+            [("TERMINFO_DIRS", None), ("TERMINFO", Some(temp_dir))],
+            || {
+                assert_eq!(
+                    locate_with_fallback(TERM_NAME),
+                    Ok((terminfo_file.clone(), "no-such-terminal".to_string()))
+                );
+                assert_eq!(
+                    locate_with_fallback("no-such-terminal"),
+                    Ok((terminfo_file, "no-such-terminal".to_string()))
+                );
+                assert_eq!(
+                    locate_with_fallback("no-such-thing-123"),
+                    Err(Error::FileNotFound)
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn iter_terminals() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir = temp_dir.path();
+        let standard = install("myterm", b"1", temp_dir).unwrap();
+        let hex_dir = temp_dir.join("6e");
+        create_dir(&hex_dir).unwrap();
+        File::create(hex_dir.join(TERM_NAME)).unwrap();
+        create_dir(hex_dir.join("subdir")).unwrap();
+        fs::write(temp_dir.join("not-a-leaf"), b"").unwrap();
+        let missing_dir = temp_dir.join("missing");
+        let terminfo_dirs = format!("{}:{}", missing_dir.display(), temp_dir.display());
+
+        temp_env::with_vars(
+            [
+                ("TERMINFO_DIRS", Some(terminfo_dirs.as_str())),
+                ("TERMINFO", None),
+                ("HOME", Some(missing_dir.to_str().unwrap())),
+            ],
+            || {
+                let mut found: Vec<_> = iter_all_terminals()
+                    .filter(|(_, path)| path.starts_with(temp_dir))
+                    .collect();
+                found.sort();
+                assert_eq!(
+                    found,
+                    vec![
+                        ("myterm".into(), standard.clone()),
+                        (TERM_NAME.into(), hex_dir.join(TERM_NAME)),
+                    ]
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn fallback_empty_name() {
+        assert_eq!(locate_with_fallback(""), Err(Error::InvalidTerminalName));
+        assert_eq!(locate_with_fallback("-"), Err(Error::FileNotFound));
+    }
+
+    #[test]
+    fn install_and_locate() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir = temp_dir.path();
+        let installed = install(TERM_NAME, b"data", temp_dir).unwrap();
+        assert_eq!(installed, temp_dir.join("n").join(TERM_NAME));
+        assert_eq!(fs::read(&installed).unwrap(), b"data");
+
+        temp_env::with_vars(
+            [("TERMINFO_DIRS", None), ("TERMINFO", Some(temp_dir))],
+            || {
+                assert_eq!(locate(TERM_NAME), Ok(installed));
+            },
+        );
+    }
+
+    #[test]
+    fn install_and_locate_non_ascii() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir = temp_dir.path();
+        let installed = install("émoi", b"data", temp_dir).unwrap();
+        assert_eq!(installed, temp_dir.join("\u{c3}").join("émoi"));
+
+        temp_env::with_vars(
+            [("TERMINFO_DIRS", None), ("TERMINFO", Some(temp_dir))],
+            || {
+                assert_eq!(locate("émoi"), Ok(installed));
+            },
+        );
+    }
+
+    #[test]
+    fn load_current_entry() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir = temp_dir.path();
+        let mut terminfo = crate::parse::Terminfo::new();
+        terminfo.numbers.insert("cols", 80);
+        install(TERM_NAME, &terminfo.to_bytes().unwrap(), temp_dir).unwrap();
+
+        temp_env::with_vars(
+            [
+                ("TERM", Some(TERM_NAME)),
+                ("TERMINFO_DIRS", None),
+                ("TERMINFO", Some(temp_dir.to_str().unwrap())),
+            ],
+            || {
+                let loaded = load_current().unwrap();
+                assert_eq!(loaded.numbers.get("cols"), Some(&80));
+            },
+        );
+        temp_env::with_var("TERM", Some(""), || {
+            assert!(matches!(
+                load_current(),
+                Err(crate::Error::Locate(Error::InvalidTerminalName))
+            ));
+        });
+        temp_env::with_var_unset("TERM", || {
+            assert!(matches!(load_current(), Err(crate::Error::Environment(_))));
+        });
+    }
+
+    #[test]
+    fn install_invalid_name() {
+        let temp_dir = tempdir().unwrap();
+        let temp_dir = temp_dir.path();
+        for name in ["", ".", "..", "a/b", "a\0b"] {
+            assert_eq!(
+                install(name, b"data", temp_dir),
+                Err(Error::InvalidTerminalName)
+            );
+        }
+    }
+
     #[test]
     fn found_standard_layout_terminfo_variable() {
         let temp_dir = tempdir().unwrap();
@@ -251,6 +562,91 @@ mod test {
         );
     }
 
+    #[test]
+    fn default_dirs() {
+        assert_eq!(
+            default_directories(),
+            [
+                "/etc/terminfo",
+                "/lib/terminfo",
+                "/usr/share/terminfo",
+                "/usr/lib/terminfo",
+                "/boot/system/data/terminfo",
+            ]
+        );
+    }
+
+    #[test]
+    fn search_order_without_home() {
+        temp_env::with_vars(
+            [
+                ("TERMINFO_DIRS", None),
+                ("TERMINFO", Some("/my/terminfo")),
+                ("HOME", None),
+            ],
+            || {
+                let mut expected_dirs = vec![PathBuf::from("/my/terminfo")];
+                expected_dirs.extend(env::home_dir().map(|home_dir| home_dir.join(".terminfo")));
+                expected_dirs.extend(default_directories().iter().map(PathBuf::from));
+                assert_eq!(search_directories(), expected_dirs);
+            },
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn search_order_windows() {
+        temp_env::with_vars(
+            [
+                ("TERMINFO_DIRS", None),
+                ("TERMINFO", None),
+                ("LOCALAPPDATA", Some(r"C:\Users\user\AppData\Local")),
+                ("APPDATA", Some(r"C:\Users\user\AppData\Roaming")),
+            ],
+            || {
+                let dirs = search_directories();
+                let local = dirs
+                    .iter()
+                    .position(|dir| dir == Path::new(r"C:\Users\user\AppData\Local\terminfo"));
+                let roaming = dirs
+                    .iter()
+                    .position(|dir| dir == Path::new(r"C:\Users\user\AppData\Roaming\terminfo"));
+                assert!(local.is_some());
+                assert_eq!(roaming, local.map(|index| index + 1));
+            },
+        );
+    }
+
+    #[test]
+    fn search_order_without_duplicates() {
+        let expected_dirs: Vec<PathBuf> = [
+            "/usr/share/terminfo/",
+            "/home/user/.terminfo",
+            "/my/terminfo1",
+            "/etc/terminfo",
+            "/lib/terminfo",
+            "/usr/lib/terminfo",
+            "/boot/system/data/terminfo",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+
+        temp_env::with_vars(
+            [
+                (
+                    "TERMINFO_DIRS",
+                    Some("/my/terminfo1:/usr/share//terminfo::/my/terminfo1/:/home/user/.terminfo"),
+                ),
+                ("TERMINFO", Some("/usr/share/terminfo/")),
+                ("HOME", Some("/home/user")),
+            ],
+            || {
+                assert_eq!(search_directories(), expected_dirs);
+            },
+        );
+    }
+
     #[test]
     fn search_order_with_empty_element() {
         let expected_dirs: Vec<PathBuf> = [
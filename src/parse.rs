@@ -9,29 +9,37 @@
 //! Parsing terminfo database files
 
 use std::{
-    collections::{BTreeMap, BTreeSet},
-    io::{Cursor, Read, Seek, SeekFrom},
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashSet},
+    env,
+    fmt::Write as _,
+    fs,
+    io::{Cursor, Read, Seek},
     mem,
+    sync::Arc,
 };
 
+use crate::expand::{self, CompiledCap, ExpandContext, Parameter, required_params};
+
 const ABSENT_ENTRY: i32 = -1;
-const CANCELED_ENTRY: i32 = -2;
+pub(crate) const CANCELED_ENTRY: i32 = -2;
+pub(crate) const CANCELED_BOOLEAN: u8 = CANCELED_ENTRY as u8;
 
-const BOOL_NAMES: [&str; 44] = [
+pub(crate) const BOOL_NAMES: [&str; 44] = [
     "bw", "am", "xsb", "xhp", "xenl", "eo", "gn", "hc", "km", "hs", "in", "db", "da", "mir",
     "msgr", "os", "eslok", "xt", "hz", "ul", "xon", "nxon", "mc5i", "chts", "nrrmc", "npc",
     "ndscr", "ccc", "bce", "hls", "xhpa", "crxm", "daisy", "xvpa", "sam", "cpix", "lpix", "OTbs",
     "OTns", "OTnc", "OTMT", "OTNL", "OTpt", "OTxr",
 ];
 
-const NUMBER_NAMES: [&str; 39] = [
+pub(crate) const NUMBER_NAMES: [&str; 39] = [
     "cols", "it", "lines", "lm", "xmc", "pb", "vt", "wsl", "nlab", "lh", "lw", "ma", "wnum",
     "colors", "pairs", "ncv", "bufsz", "spinv", "spinh", "maddr", "mjump", "mcs", "mls", "npins",
     "orc", "orl", "orhi", "orvi", "cps", "widcs", "btns", "bitwin", "bitype", "UTug", "OTdC",
     "OTdN", "OTdB", "OTdT", "OTkn",
 ];
 
-const STRING_NAMES: [&str; 414] = [
+pub(crate) const STRING_NAMES: [&str; 414] = [
     "cbt", "bel", "cr", "csr", "tbc", "clear", "el", "ed", "hpa", "cmdch", "cup", "cud1", "home",
     "civis", "cub1", "mrcup", "cnorm", "cuf1", "ll", "cuu1", "cvvis", "dch1", "dl1", "dsl", "hd",
     "smacs", "blink", "bold", "smcup", "smdc", "dim", "smir", "invis", "prot", "rev", "smso",
@@ -69,21 +77,540 @@ const STRING_NAMES: [&str; 414] = [
     "OTG4", "OTGR", "OTGL", "OTGU", "OTGD", "OTGH", "OTGV", "OTGC", "meml", "memu", "box1",
 ];
 
+pub(crate) const BOOL_LONG_NAMES: [&str; 44] = [
+    "auto_left_margin",
+    "auto_right_margin",
+    "no_esc_ctlc",
+    "ceol_standout_glitch",
+    "eat_newline_glitch",
+    "erase_overstrike",
+    "generic_type",
+    "hard_copy",
+    "has_meta_key",
+    "has_status_line",
+    "insert_null_glitch",
+    "memory_above",
+    "memory_below",
+    "move_insert_mode",
+    "move_standout_mode",
+    "over_strike",
+    "status_line_esc_ok",
+    "dest_tabs_magic_smso",
+    "tilde_glitch",
+    "transparent_underline",
+    "xon_xoff",
+    "needs_xon_xoff",
+    "prtr_silent",
+    "hard_cursor",
+    "non_rev_rmcup",
+    "no_pad_char",
+    "non_dest_scroll_region",
+    "can_change",
+    "back_color_erase",
+    "hue_lightness_saturation",
+    "col_addr_glitch",
+    "cr_cancels_micro_mode",
+    "has_print_wheel",
+    "row_addr_glitch",
+    "semi_auto_right_margin",
+    "cpi_changes_res",
+    "lpi_changes_res",
+    "backspaces_with_bs",
+    "crt_no_scrolling",
+    "no_correctly_working_cr",
+    "gnu_has_meta_key",
+    "linefeed_is_newline",
+    "has_hardware_tabs",
+    "return_does_clr_eol",
+];
+
+pub(crate) const NUMBER_LONG_NAMES: [&str; 39] = [
+    "columns",
+    "init_tabs",
+    "lines",
+    "lines_of_memory",
+    "magic_cookie_glitch",
+    "padding_baud_rate",
+    "virtual_terminal",
+    "width_status_line",
+    "num_labels",
+    "label_height",
+    "label_width",
+    "max_attributes",
+    "maximum_windows",
+    "max_colors",
+    "max_pairs",
+    "no_color_video",
+    "buffer_capacity",
+    "dot_vert_spacing",
+    "dot_horz_spacing",
+    "max_micro_address",
+    "max_micro_jump",
+    "micro_col_size",
+    "micro_line_size",
+    "number_of_pins",
+    "output_res_char",
+    "output_res_line",
+    "output_res_horz_inch",
+    "output_res_vert_inch",
+    "print_rate",
+    "wide_char_size",
+    "buttons",
+    "bit_image_entwining",
+    "bit_image_type",
+    "magic_cookie_glitch_ul",
+    "carriage_return_delay",
+    "new_line_delay",
+    "backspace_delay",
+    "horizontal_tab_delay",
+    "number_of_function_keys",
+];
+
+pub(crate) const STRING_LONG_NAMES: [&str; 414] = [
+    "back_tab",
+    "bell",
+    "carriage_return",
+    "change_scroll_region",
+    "clear_all_tabs",
+    "clear_screen",
+    "clr_eol",
+    "clr_eos",
+    "column_address",
+    "command_character",
+    "cursor_address",
+    "cursor_down",
+    "cursor_home",
+    "cursor_invisible",
+    "cursor_left",
+    "cursor_mem_address",
+    "cursor_normal",
+    "cursor_right",
+    "cursor_to_ll",
+    "cursor_up",
+    "cursor_visible",
+    "delete_character",
+    "delete_line",
+    "dis_status_line",
+    "down_half_line",
+    "enter_alt_charset_mode",
+    "enter_blink_mode",
+    "enter_bold_mode",
+    "enter_ca_mode",
+    "enter_delete_mode",
+    "enter_dim_mode",
+    "enter_insert_mode",
+    "enter_secure_mode",
+    "enter_protected_mode",
+    "enter_reverse_mode",
+    "enter_standout_mode",
+    "enter_underline_mode",
+    "erase_chars",
+    "exit_alt_charset_mode",
+    "exit_attribute_mode",
+    "exit_ca_mode",
+    "exit_delete_mode",
+    "exit_insert_mode",
+    "exit_standout_mode",
+    "exit_underline_mode",
+    "flash_screen",
+    "form_feed",
+    "from_status_line",
+    "init_1string",
+    "init_2string",
+    "init_3string",
+    "init_file",
+    "insert_character",
+    "insert_line",
+    "insert_padding",
+    "key_backspace",
+    "key_catab",
+    "key_clear",
+    "key_ctab",
+    "key_dc",
+    "key_dl",
+    "key_down",
+    "key_eic",
+    "key_eol",
+    "key_eos",
+    "key_f0",
+    "key_f1",
+    "key_f10",
+    "key_f2",
+    "key_f3",
+    "key_f4",
+    "key_f5",
+    "key_f6",
+    "key_f7",
+    "key_f8",
+    "key_f9",
+    "key_home",
+    "key_ic",
+    "key_il",
+    "key_left",
+    "key_ll",
+    "key_npage",
+    "key_ppage",
+    "key_right",
+    "key_sf",
+    "key_sr",
+    "key_stab",
+    "key_up",
+    "keypad_local",
+    "keypad_xmit",
+    "lab_f0",
+    "lab_f1",
+    "lab_f10",
+    "lab_f2",
+    "lab_f3",
+    "lab_f4",
+    "lab_f5",
+    "lab_f6",
+    "lab_f7",
+    "lab_f8",
+    "lab_f9",
+    "meta_off",
+    "meta_on",
+    "newline",
+    "pad_char",
+    "parm_dch",
+    "parm_delete_line",
+    "parm_down_cursor",
+    "parm_ich",
+    "parm_index",
+    "parm_insert_line",
+    "parm_left_cursor",
+    "parm_right_cursor",
+    "parm_rindex",
+    "parm_up_cursor",
+    "pkey_key",
+    "pkey_local",
+    "pkey_xmit",
+    "print_screen",
+    "prtr_off",
+    "prtr_on",
+    "repeat_char",
+    "reset_1string",
+    "reset_2string",
+    "reset_3string",
+    "reset_file",
+    "restore_cursor",
+    "row_address",
+    "save_cursor",
+    "scroll_forward",
+    "scroll_reverse",
+    "set_attributes",
+    "set_tab",
+    "set_window",
+    "tab",
+    "to_status_line",
+    "underline_char",
+    "up_half_line",
+    "init_prog",
+    "key_a1",
+    "key_a3",
+    "key_b2",
+    "key_c1",
+    "key_c3",
+    "prtr_non",
+    "char_padding",
+    "acs_chars",
+    "plab_norm",
+    "key_btab",
+    "enter_xon_mode",
+    "exit_xon_mode",
+    "enter_am_mode",
+    "exit_am_mode",
+    "xon_character",
+    "xoff_character",
+    "ena_acs",
+    "label_on",
+    "label_off",
+    "key_beg",
+    "key_cancel",
+    "key_close",
+    "key_command",
+    "key_copy",
+    "key_create",
+    "key_end",
+    "key_enter",
+    "key_exit",
+    "key_find",
+    "key_help",
+    "key_mark",
+    "key_message",
+    "key_move",
+    "key_next",
+    "key_open",
+    "key_options",
+    "key_previous",
+    "key_print",
+    "key_redo",
+    "key_reference",
+    "key_refresh",
+    "key_replace",
+    "key_restart",
+    "key_resume",
+    "key_save",
+    "key_suspend",
+    "key_undo",
+    "key_sbeg",
+    "key_scancel",
+    "key_scommand",
+    "key_scopy",
+    "key_screate",
+    "key_sdc",
+    "key_sdl",
+    "key_select",
+    "key_send",
+    "key_seol",
+    "key_sexit",
+    "key_sfind",
+    "key_shelp",
+    "key_shome",
+    "key_sic",
+    "key_sleft",
+    "key_smessage",
+    "key_smove",
+    "key_snext",
+    "key_soptions",
+    "key_sprevious",
+    "key_sprint",
+    "key_sredo",
+    "key_sreplace",
+    "key_sright",
+    "key_srsume",
+    "key_ssave",
+    "key_ssuspend",
+    "key_sundo",
+    "req_for_input",
+    "key_f11",
+    "key_f12",
+    "key_f13",
+    "key_f14",
+    "key_f15",
+    "key_f16",
+    "key_f17",
+    "key_f18",
+    "key_f19",
+    "key_f20",
+    "key_f21",
+    "key_f22",
+    "key_f23",
+    "key_f24",
+    "key_f25",
+    "key_f26",
+    "key_f27",
+    "key_f28",
+    "key_f29",
+    "key_f30",
+    "key_f31",
+    "key_f32",
+    "key_f33",
+    "key_f34",
+    "key_f35",
+    "key_f36",
+    "key_f37",
+    "key_f38",
+    "key_f39",
+    "key_f40",
+    "key_f41",
+    "key_f42",
+    "key_f43",
+    "key_f44",
+    "key_f45",
+    "key_f46",
+    "key_f47",
+    "key_f48",
+    "key_f49",
+    "key_f50",
+    "key_f51",
+    "key_f52",
+    "key_f53",
+    "key_f54",
+    "key_f55",
+    "key_f56",
+    "key_f57",
+    "key_f58",
+    "key_f59",
+    "key_f60",
+    "key_f61",
+    "key_f62",
+    "key_f63",
+    "clr_bol",
+    "clear_margins",
+    "set_left_margin",
+    "set_right_margin",
+    "label_format",
+    "set_clock",
+    "display_clock",
+    "remove_clock",
+    "create_window",
+    "goto_window",
+    "hangup",
+    "dial_phone",
+    "quick_dial",
+    "tone",
+    "pulse",
+    "flash_hook",
+    "fixed_pause",
+    "wait_tone",
+    "user0",
+    "user1",
+    "user2",
+    "user3",
+    "user4",
+    "user5",
+    "user6",
+    "user7",
+    "user8",
+    "user9",
+    "orig_pair",
+    "orig_colors",
+    "initialize_color",
+    "initialize_pair",
+    "set_color_pair",
+    "set_foreground",
+    "set_background",
+    "change_char_pitch",
+    "change_line_pitch",
+    "change_res_horz",
+    "change_res_vert",
+    "define_char",
+    "enter_doublewide_mode",
+    "enter_draft_quality",
+    "enter_italics_mode",
+    "enter_leftward_mode",
+    "enter_micro_mode",
+    "enter_near_letter_quality",
+    "enter_normal_quality",
+    "enter_shadow_mode",
+    "enter_subscript_mode",
+    "enter_superscript_mode",
+    "enter_upward_mode",
+    "exit_doublewide_mode",
+    "exit_italics_mode",
+    "exit_leftward_mode",
+    "exit_micro_mode",
+    "exit_shadow_mode",
+    "exit_subscript_mode",
+    "exit_superscript_mode",
+    "exit_upward_mode",
+    "micro_column_address",
+    "micro_down",
+    "micro_left",
+    "micro_right",
+    "micro_row_address",
+    "micro_up",
+    "order_of_pins",
+    "parm_down_micro",
+    "parm_left_micro",
+    "parm_right_micro",
+    "parm_up_micro",
+    "select_char_set",
+    "set_bottom_margin",
+    "set_bottom_margin_parm",
+    "set_left_margin_parm",
+    "set_right_margin_parm",
+    "set_top_margin",
+    "set_top_margin_parm",
+    "start_bit_image",
+    "start_char_set_def",
+    "stop_bit_image",
+    "stop_char_set_def",
+    "subscript_characters",
+    "superscript_characters",
+    "these_cause_cr",
+    "zero_motion",
+    "char_set_names",
+    "key_mouse",
+    "mouse_info",
+    "req_mouse_pos",
+    "get_mouse",
+    "set_a_foreground",
+    "set_a_background",
+    "pkey_plab",
+    "device_type",
+    "code_set_init",
+    "set0_des_seq",
+    "set1_des_seq",
+    "set2_des_seq",
+    "set3_des_seq",
+    "set_lr_margin",
+    "set_tb_margin",
+    "bit_image_repeat",
+    "bit_image_newline",
+    "bit_image_carriage_return",
+    "color_names",
+    "define_bit_image_region",
+    "end_bit_image_region",
+    "set_color_band",
+    "set_page_length",
+    "display_pc_char",
+    "enter_pc_charset_mode",
+    "exit_pc_charset_mode",
+    "enter_scancode_mode",
+    "exit_scancode_mode",
+    "pc_term_options",
+    "scancode_escape",
+    "alt_scancode_esc",
+    "enter_horizontal_hl_mode",
+    "enter_left_hl_mode",
+    "enter_low_hl_mode",
+    "enter_right_hl_mode",
+    "enter_top_hl_mode",
+    "enter_vertical_hl_mode",
+    "set_a_attributes",
+    "set_pglen_inch",
+    "termcap_init2",
+    "termcap_reset",
+    "linefeed_if_not_lf",
+    "backspace_if_not_bs",
+    "other_non_function_keys",
+    "arrow_key_map",
+    "acs_ulcorner",
+    "acs_llcorner",
+    "acs_urcorner",
+    "acs_lrcorner",
+    "acs_ltee",
+    "acs_rtee",
+    "acs_btee",
+    "acs_ttee",
+    "acs_hline",
+    "acs_vline",
+    "acs_plus",
+    "memory_lock",
+    "memory_unlock",
+    "box_chars_1",
+];
+
 #[repr(u16)]
-enum TerminfoMagic {
+pub(crate) enum TerminfoMagic {
     /// Original format, 16-bit numbers
     Magic1 = 0x011a,
     /// 32-bit numbers
     Magic2 = 0x021e,
 }
 
+impl TerminfoMagic {
+    /// Return the size of numbers in bytes for the magic number
+    ///
+    /// This is the only place where magic numbers are recognized.
+    const fn number_size(magic: u16) -> Result<usize, Error> {
+        match magic {
+            magic if magic == Self::Magic1 as u16 => Ok(2),
+            magic if magic == Self::Magic2 as u16 => Ok(4),
+            _ => Err(Error::BadMagic(magic)),
+        }
+    }
+}
+
 /// Errors reported when parsing a terminfo database
 #[derive(thiserror::Error, Debug)]
 #[non_exhaustive]
 pub enum Error {
     /// The magic number is invalid or unsupported
-    #[error("Unknown magic number")]
-    BadMagic,
+    #[error("Unknown magic number {0:#06x}")]
+    BadMagic(u16),
     /// A string is not terminated by the NUL byte
     #[error("String without final NUL")]
     UnterminatedString,
@@ -93,6 +620,9 @@ pub enum Error {
     /// Boolean value not 0 or 1, probably invalid terminfo database
     #[error("Invalid boolean value {0}")]
     InvalidBooleanValue(u8),
+    /// Alignment byte is not 0, reported by `parse_strict()`
+    #[error("Invalid padding byte {0}")]
+    BadPadding(u8),
     /// Input/output error, probably truncated terminfo database
     #[error("I/O error")]
     IO(#[from] std::io::Error),
@@ -105,16 +635,197 @@ pub enum Error {
 ///
 /// Returns `Terminfo` instance with data populated from the buffer.
 pub fn parse(buffer: &[u8]) -> Result<Terminfo<'_>, Error> {
+    parse_with_padding(buffer, false)
+}
+
+/// Parse terminfo database, checking that alignment bytes are 0
+///
+/// Same as `parse()`, but `Error::BadPadding` is returned if a byte skipped
+/// for alignment is not 0, which may indicate a corrupted file.
+pub fn parse_strict(buffer: &[u8]) -> Result<Terminfo<'_>, Error> {
+    parse_with_padding(buffer, true)
+}
+
+fn parse_with_padding(buffer: &[u8], check_padding: bool) -> Result<Terminfo<'_>, Error> {
     let mut terminfo = Terminfo::new();
     let mut reader = Cursor::new(buffer);
-    terminfo.parse_base(&mut reader)?;
-    match terminfo.parse_extended(&mut reader) {
+    terminfo.parse_base(&mut reader, check_padding)?;
+    match terminfo.parse_extended(&mut reader, check_padding) {
         Ok(()) | Err(Error::IO(_)) => {} // missing extended data is OK
         Err(err) => return Err(err),
     }
     Ok(terminfo)
 }
 
+/// Parse terminfo database into an entry that doesn't borrow the buffer
+///
+/// Same as `parse()` followed by `Terminfo::into_owned()`.
+pub fn parse_owned(buffer: &[u8]) -> Result<TerminfoOwned, Error> {
+    Ok(parse(buffer)?.into_owned())
+}
+
+/// Parse terminfo database from a reader
+///
+/// The whole stream is read into an internal buffer, so an entry that doesn't
+/// borrow the buffer is returned.
+pub fn parse_reader<R: Read>(mut reader: R) -> Result<TerminfoOwned, Error> {
+    let mut buffer = vec![];
+    reader.read_to_end(&mut buffer)?;
+    parse_owned(&buffer)
+}
+
+/// Memory-mapped terminfo database file
+///
+/// The mapping is kept alive as long as this object exists, the entry returned
+/// by `terminfo()` borrows it.
+#[cfg(feature = "mmap")]
+pub struct MappedTerminfo {
+    /// Entry borrowing `mmap`, declared first so that it's dropped first
+    terminfo: Terminfo<'static>,
+    mmap: memmap2::Mmap,
+}
+
+#[cfg(feature = "mmap")]
+impl MappedTerminfo {
+    /// Return the entry parsed from the mapped file
+    #[must_use]
+    pub const fn terminfo(&self) -> &Terminfo<'_> {
+        &self.terminfo
+    }
+
+    /// Return the contents of the mapped file
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.mmap
+    }
+}
+
+/// Map terminfo database file into memory and parse it
+///
+/// This avoids copying the file for tools that scan many entries. The file
+/// must not be modified while it's mapped.
+#[cfg(feature = "mmap")]
+pub fn parse_mmap(path: impl AsRef<std::path::Path>) -> Result<MappedTerminfo, crate::Error> {
+    let file = fs::File::open(path)?;
+    // SAFETY: The mapping is read-only. Modifying the file while it's mapped
+    // is forbidden by the documentation of this function.
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    // SAFETY: The mapped memory doesn't move with `mmap` and stays valid until
+    // `mmap` is dropped, which happens after `terminfo` is dropped. The entry
+    // is only given out with the lifetime of `MappedTerminfo`.
+    let buffer: &'static [u8] = unsafe { std::slice::from_raw_parts(mmap.as_ptr(), mmap.len()) };
+    let terminfo = parse(buffer)?;
+    Ok(MappedTerminfo { terminfo, mmap })
+}
+
+/// Parse terminfo database, sharing string values with other entries
+///
+/// String values equal to those already stored in the interner share the
+/// storage with them. Use the same interner for many entries to save memory.
+pub fn parse_interned(buffer: &[u8], interner: &mut Interner) -> Result<TerminfoOwned, Error> {
+    let terminfo = parse(buffer)?;
+    Ok(TerminfoOwned::with_interner(&terminfo, interner))
+}
+
+/// Convert a string value to the notation used in terminfo source
+///
+/// Escape is shown as `\E`, other control characters as `^X` or as `\n`,
+/// `\r`, `\t`, `\b`, `\f`, space as `\s`. Characters with special meaning in
+/// the source are preceded by a backslash. Byte 0200, which stands for NUL in
+/// terminfo, is shown as `\0`, other bytes outside ASCII as octal `\nnn`.
+#[must_use]
+pub fn escape(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            0x1b => text.push_str("\\E"),
+            b'\n' => text.push_str("\\n"),
+            b'\r' => text.push_str("\\r"),
+            b'\t' => text.push_str("\\t"),
+            0x08 => text.push_str("\\b"),
+            0x0c => text.push_str("\\f"),
+            b' ' => text.push_str("\\s"),
+            0x80 => text.push_str("\\0"),
+            b'\\' | b'^' | b',' | b':' => {
+                text.push('\\');
+                text.push(char::from(byte));
+            }
+            1..=0x1f => {
+                text.push('^');
+                text.push(char::from(byte + 0x40));
+            }
+            0x7f => text.push_str("^?"),
+            0x21..=0x7e => text.push(char::from(byte)),
+            _ => _ = write!(text, "\\{byte:03o}"),
+        }
+    }
+    text
+}
+
+/// Convert a string value from the notation used in terminfo source
+///
+/// This is the reverse of `escape()`. `\e` is accepted for escape, `\l` for
+/// newline and `\a` for bell. Like in `tic`, `\0` and `\000` produce byte 0200,
+/// as NUL cannot be stored in terminfo. Unknown escapes produce the escaped
+/// character.
+#[must_use]
+pub fn unescape(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut iter = text.bytes().peekable();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'\\' => match iter.next() {
+                Some(b'E' | b'e') => bytes.push(0x1b),
+                Some(b'n' | b'l') => bytes.push(b'\n'),
+                Some(b'r') => bytes.push(b'\r'),
+                Some(b't') => bytes.push(b'\t'),
+                Some(b'b') => bytes.push(0x08),
+                Some(b'f') => bytes.push(0x0c),
+                Some(b's') => bytes.push(b' '),
+                Some(b'a') => bytes.push(0x07),
+                Some(digit @ b'0'..=b'7') => {
+                    let mut value = u32::from(digit - b'0');
+                    for _ in 0..2 {
+                        let Some(digit @ b'0'..=b'7') = iter.peek().copied() else {
+                            break;
+                        };
+                        value = value * 8 + u32::from(digit - b'0');
+                        iter.next();
+                    }
+                    // Octal values above 0377 are truncated like in `tic`
+                    bytes.push(if value == 0 { 0x80 } else { value as u8 });
+                }
+                Some(other) => bytes.push(other),
+                None => bytes.push(b'\\'),
+            },
+            b'^' => match iter.next() {
+                Some(b'?') => bytes.push(0x7f),
+                Some(other) => bytes.push(other & 0x1f),
+                None => bytes.push(b'^'),
+            },
+            _ => bytes.push(byte),
+        }
+    }
+    bytes
+}
+
+/// Return the kind of a predefined capability
+///
+/// Returns `None` for names that are not in the terminfo tables, such as
+/// extended capabilities and misspelled names. Only short names are accepted.
+#[must_use]
+pub fn is_known_cap(name: &str) -> Option<Kind> {
+    if BOOL_NAMES.contains(&name) {
+        Some(Kind::Boolean)
+    } else if NUMBER_NAMES.contains(&name) {
+        Some(Kind::Number)
+    } else if STRING_NAMES.contains(&name) {
+        Some(Kind::String)
+    } else {
+        None
+    }
+}
+
 fn read_u8(reader: &mut impl Read) -> Result<u8, Error> {
     let mut buffer = [0u8; 1];
     reader.read_exact(&mut buffer)?;
@@ -129,24 +840,86 @@ fn read_le16(reader: &mut impl Read) -> Result<u16, Error> {
 }
 
 fn read_slice<'a>(reader: &mut Cursor<&'a [u8]>, size: usize) -> Result<&'a [u8], Error> {
-    let start = reader.position() as usize;
-    let end = reader.seek(SeekFrom::Current(size as i64))? as usize;
-    let buffer = &reader.get_ref();
-    match buffer.get(start..end) {
-        Some(slice) => Ok(slice),
-        None => Err(Error::UnsupportedFormat),
-    }
+    let buffer = *reader.get_ref();
+    let slice = usize::try_from(reader.position())
+        .ok()
+        .and_then(|start| buffer.get(start..start.checked_add(size)?))
+        .ok_or(Error::UnsupportedFormat)?;
+    reader.seek_relative(slice.len() as i64)?;
+    Ok(slice)
 }
 
 fn get_string(string_table: &[u8], offset: usize) -> Result<&[u8], Error> {
     let Some(string_slice) = string_table.get(offset..) else {
         return Err(Error::UnsupportedFormat);
     };
-    if let Some(string_length) = &string_slice.iter().position(|c| *c == b'\0') {
-        Ok(&string_table[offset..offset + string_length])
-    } else {
-        Err(Error::UnterminatedString)
+    match string_slice.iter().position(|c| *c == b'\0') {
+        Some(string_length) => Ok(&string_slice[..string_length]),
+        None => Err(Error::UnterminatedString),
+    }
+}
+
+/// Get the name of an extended capability from the names table
+///
+/// Names must start at a string boundary, so that they cannot alias the tail
+/// of another name.
+fn get_name(names_table: &[u8], offset: usize) -> Result<&str, Error> {
+    if offset > 0 && names_table.get(offset - 1) != Some(&b'\0') {
+        return Err(Error::UnsupportedFormat);
     }
+    Ok(str::from_utf8(get_string(names_table, offset)?)?)
+}
+
+/// Read a positive screen size from the environment variable
+fn size_from_env(name: &str) -> Option<i32> {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|&size| size > 0)
+}
+
+/// Translate the long name of a predefined capability to its short name
+fn short_name(
+    long_names: &[&str],
+    short_names: &[&'static str],
+    long_name: &str,
+) -> Option<&'static str> {
+    let index = long_names.iter().position(|&name| name == long_name)?;
+    short_names.get(index).copied()
+}
+
+/// Present names in the predefined order, then extended and other names
+fn ordered_names<'a, 's>(
+    base: &'s [&'a str],
+    extended: &'s [&'a str],
+    keys: impl Iterator<Item = &'a str> + 's,
+    contains: impl Fn(&str) -> bool + Copy + 's,
+) -> impl Iterator<Item = &'a str> + 's {
+    let base_names = base.iter().copied().filter(move |name| contains(name));
+    let extended_names = extended
+        .iter()
+        .copied()
+        .filter(move |name| contains(name) && !base.contains(name));
+    let other_names = keys.filter(move |name| !base.contains(name) && !extended.contains(name));
+    base_names.chain(extended_names).chain(other_names)
+}
+
+/// Borrow the name from the table if it's there, allocate it otherwise
+fn owned_name(names: &[&'static str], name: &str) -> Cow<'static, str> {
+    names.iter().find(|&&known| known == name).map_or_else(
+        || Cow::Owned(name.to_owned()),
+        |&known| Cow::Borrowed(known),
+    )
+}
+
+/// Check whether the name is one of the obsolete capabilities
+fn is_obsolete(name: &str) -> bool {
+    name.starts_with("OT") || name.starts_with("UT")
+}
+
+/// Check whether the offset marks a canceled string
+fn is_canceled_offset(offset: u16) -> bool {
+    i32::from(offset as i16) == CANCELED_ENTRY
 }
 
 /// Convert ABSENT and CANCELED to None
@@ -157,49 +930,553 @@ fn check_offset(size: u16) -> Option<usize> {
     }
 }
 
-/// Skip a byte if needed to ensure 2-byte alignment
-fn align_cursor(reader: &mut Cursor<&[u8]>) -> Result<(), Error> {
+/// Skip a byte if needed to ensure 2-byte alignment, optionally check it's 0
+fn align_cursor(reader: &mut Cursor<&[u8]>, check_padding: bool) -> Result<(), Error> {
     let position = reader.position();
-    if !position.is_multiple_of(2) {
+    if position.is_multiple_of(2) {
+        return Ok(());
+    }
+    if check_padding {
+        match read_u8(reader)? {
+            0 => {}
+            value => return Err(Error::BadPadding(value)),
+        }
+    } else {
         reader.seek_relative(1)?;
     }
     Ok(())
 }
 
+/// Kind of a capability
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Boolean,
+    Number,
+    String,
+}
+
+/// String capability compiled for repeated expansion
+///
+/// Returned by `Terminfo::cap()` and `TerminfoOwned::cap()`.
+#[derive(Clone, Debug)]
+pub struct Capability<'a> {
+    bytes: &'a [u8],
+    compiled: Result<CompiledCap, expand::Error>,
+}
+
+impl<'a> Capability<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            compiled: CompiledCap::compile(bytes),
+        }
+    }
+
+    /// Return the value of the capability
+    #[must_use]
+    pub const fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// Return the compiled capability, or the error if it cannot be compiled
+    pub fn compiled(&self) -> Result<&CompiledCap, expand::Error> {
+        self.compiled.as_ref().map_err(Clone::clone)
+    }
+
+    /// Expand the capability in the given context
+    ///
+    /// Same as `CompiledCap::expand()`, compilation errors are returned here.
+    pub fn expand(
+        &self,
+        context: &mut ExpandContext,
+        params: &[Parameter<'_>],
+    ) -> Result<Vec<u8>, expand::Error> {
+        self.compiled()?.expand(context, params)
+    }
+}
+
+/// Problem found by `Terminfo::validate()`
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ValidationWarning {
+    /// A screen size capability is not positive
+    NonPositiveSize(&'static str),
+    /// Color setting capability is present without positive `colors`
+    MissingColors(&'static str),
+    /// Color pair capability is present without positive `pairs`
+    MissingPairs(&'static str),
+    /// A string capability cannot be compiled
+    MalformedString(&'static str),
+    /// A string capability uses an unexpected number of parameters
+    ParameterCount {
+        name: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+/// State of a numeric capability in the entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NumberState {
+    /// The capability has a value
+    Present(i32),
+    /// The capability is explicitly canceled
+    Canceled,
+    /// The capability is not in the entry
+    Absent,
+}
+
 /// Parsed terminfo entry
+///
+/// The maps are sorted by name. Use `ordered_booleans()`, `ordered_numbers()`
+/// and `ordered_strings()` to get the capabilities in the file order.
 #[derive(Debug)]
 pub struct Terminfo<'a> {
     pub booleans: BTreeSet<&'a str>,
     pub numbers: BTreeMap<&'a str, i32>,
     pub strings: BTreeMap<&'a str, &'a [u8]>,
+    pub(crate) term_names: &'a str,
+    pub(crate) canceled_booleans: BTreeSet<&'a str>,
+    pub(crate) canceled_numbers: BTreeSet<&'a str>,
+    pub(crate) canceled_strings: BTreeSet<&'a str>,
+    /// Names of the extended capabilities in the file order
+    extended_booleans: Vec<&'a str>,
+    extended_numbers: Vec<&'a str>,
+    extended_strings: Vec<&'a str>,
     number_size: usize,
 }
 
 impl<'a> Terminfo<'a> {
-    fn new() -> Self {
+    /// Parse terminfo database from the supplied buffer
+    ///
+    /// Same as the `parse()` function.
+    pub fn parse(buffer: &'a [u8]) -> Result<Self, Error> {
+        parse(buffer)
+    }
+
+    /// Return the kind of the capability if it's present
+    #[must_use]
+    pub fn capability_kind(&self, name: &str) -> Option<Kind> {
+        if self.booleans.contains(name) {
+            Some(Kind::Boolean)
+        } else if self.numbers.contains_key(name) {
+            Some(Kind::Number)
+        } else if self.strings.contains_key(name) {
+            Some(Kind::String)
+        } else {
+            None
+        }
+    }
+
+    /// Return the string capability compiled for repeated expansion
+    ///
+    /// Returns `None` if the capability is absent. Compilation errors are
+    /// reported when the capability is expanded.
+    #[must_use]
+    pub fn cap(&self, name: &str) -> Option<Capability<'a>> {
+        self.get_string(name).map(Capability::new)
+    }
+
+    /// Return the first of the names that is present as a capability of any kind
+    ///
+    /// This is useful for features with several names, such as `Tc` and `RGB`.
+    #[must_use]
+    pub fn any_of(&self, names: &[&str]) -> Option<&'a str> {
+        names.iter().find_map(|&name| {
+            self.booleans
+                .get(name)
+                .or_else(|| self.numbers.get_key_value(name).map(|(key, _)| key))
+                .or_else(|| self.strings.get_key_value(name).map(|(key, _)| key))
+                .copied()
+        })
+    }
+
+    /// Iterate over the terminal name and its aliases
+    ///
+    /// The description, if present, is not included.
+    pub fn names(&self) -> impl Iterator<Item = &'a str> + use<'a> {
+        let mut fields: Vec<_> = self.term_names.split('|').collect();
+        if fields.len() > 1 {
+            fields.pop();
+        }
+        fields.into_iter().filter(|name| !name.is_empty())
+    }
+
+    /// Return the description of the terminal
+    ///
+    /// The description is the last of the `|` separated fields in the header,
+    /// provided there is more than one field.
+    #[must_use]
+    pub fn description(&self) -> Option<&'a str> {
+        self.term_names
+            .rsplit_once('|')
+            .map(|(_, description)| description)
+    }
+
+    /// Fill in capabilities missing from the entry using the base entry
+    ///
+    /// This is similar to resolving `use=` by `tic`. Capabilities present in
+    /// the entry take precedence over the base entry. Capabilities canceled in
+    /// the entry are not inherited, so a boolean that is present in the base
+    /// entry and absent in this entry becomes present unless it's canceled.
+    pub fn merge(&mut self, base: &Self) {
+        for &name in &base.booleans {
+            if !self.canceled_booleans.contains(name) {
+                self.booleans.insert(name);
+            }
+        }
+        for (&name, &value) in &base.numbers {
+            if !self.canceled_numbers.contains(name) {
+                self.numbers.entry(name).or_insert(value);
+            }
+        }
+        for (&name, &value) in &base.strings {
+            if !self.canceled_strings.contains(name) {
+                self.strings.entry(name).or_insert(value);
+            }
+        }
+    }
+
+    /// Check whether a boolean capability is canceled in the entry
+    #[must_use]
+    pub fn boolean_is_canceled(&self, name: &str) -> bool {
+        self.canceled_booleans.contains(name)
+    }
+
+    /// Check whether a numeric capability is canceled in the entry
+    #[must_use]
+    pub fn number_is_canceled(&self, name: &str) -> bool {
+        self.canceled_numbers.contains(name)
+    }
+
+    /// Check whether a string capability is canceled in the entry
+    #[must_use]
+    pub fn string_is_canceled(&self, name: &str) -> bool {
+        self.canceled_strings.contains(name)
+    }
+
+    /// Return the value of a boolean capability
+    ///
+    /// Absent boolean capabilities are false.
+    #[must_use]
+    pub fn get_bool(&self, name: &str) -> bool {
+        self.booleans.contains(name)
+    }
+
+    /// Return the value of a numeric capability if it's present
+    #[must_use]
+    pub fn get_number(&self, name: &str) -> Option<i32> {
+        self.numbers.get(name).copied()
+    }
+
+    /// Return the number of columns (`cols`)
+    ///
+    /// This is the canonical way to read the width of the screen from the
+    /// entry. The actual size of the terminal window may differ.
+    #[must_use]
+    pub fn columns(&self) -> Option<i32> {
+        self.get_number("cols")
+    }
+
+    /// Return the number of lines (`lines`)
+    ///
+    /// This is the canonical way to read the height of the screen from the
+    /// entry. The actual size of the terminal window may differ.
+    #[must_use]
+    pub fn lines(&self) -> Option<i32> {
+        self.get_number("lines")
+    }
+
+    /// Return the number of columns from `COLUMNS` or the `cols` capability
+    ///
+    /// The environment variable is used if it's a positive number, like in
+    /// ncurses.
+    #[must_use]
+    pub fn columns_from_env(&self) -> Option<i32> {
+        size_from_env("COLUMNS").or_else(|| self.columns())
+    }
+
+    /// Return the number of lines from `LINES` or the `lines` capability
+    ///
+    /// The environment variable is used if it's a positive number, like in
+    /// ncurses.
+    #[must_use]
+    pub fn lines_from_env(&self) -> Option<i32> {
+        size_from_env("LINES").or_else(|| self.lines())
+    }
+
+    /// Return the maximal number of colors (`colors`)
+    #[must_use]
+    pub fn max_colors(&self) -> Option<i32> {
+        self.get_number("colors")
+    }
+
+    /// Check the entry for common inconsistencies
+    ///
+    /// Screen size capabilities must be positive, color setting capabilities
+    /// require `colors` and `pairs`, and common parameterized capabilities
+    /// must use the expected number of parameters. An empty vector is returned
+    /// if no problems are found.
+    #[must_use]
+    pub fn validate(&self) -> Vec<ValidationWarning> {
+        let mut warnings = vec![];
+        for name in ["cols", "lines"] {
+            if self.get_number(name).is_some_and(|size| size <= 0) {
+                warnings.push(ValidationWarning::NonPositiveSize(name));
+            }
+        }
+
+        let has_colors = self.max_colors().is_some_and(|colors| colors > 0);
+        for name in ["setaf", "setab", "setf", "setb", "initc"] {
+            if self.strings.contains_key(name) && !has_colors {
+                warnings.push(ValidationWarning::MissingColors(name));
+            }
+        }
+        let has_pairs = self.get_number("pairs").is_some_and(|pairs| pairs > 0);
+        for name in ["scp", "initp"] {
+            if self.strings.contains_key(name) && !has_pairs {
+                warnings.push(ValidationWarning::MissingPairs(name));
+            }
+        }
+
+        let param_counts = [
+            ("cup", 2),
+            ("csr", 2),
+            ("hpa", 1),
+            ("vpa", 1),
+            ("setaf", 1),
+            ("setab", 1),
+            ("setf", 1),
+            ("setb", 1),
+        ];
+        for (name, expected) in param_counts {
+            let Some(cap) = self.get_string(name) else {
+                continue;
+            };
+            match required_params(cap) {
+                Ok(usage) if usage.count() != expected => {
+                    warnings.push(ValidationWarning::ParameterCount {
+                        name,
+                        expected,
+                        actual: usage.count(),
+                    });
+                }
+                Ok(_) => {}
+                Err(_) => warnings.push(ValidationWarning::MalformedString(name)),
+            }
+        }
+        warnings
+    }
+
+    /// Return the value of a numeric capability, distinguishing canceled ones
+    #[must_use]
+    pub fn get_number_raw(&self, name: &str) -> NumberState {
+        if let Some(&value) = self.numbers.get(name) {
+            NumberState::Present(value)
+        } else if self.canceled_numbers.contains(name) {
+            NumberState::Canceled
+        } else {
+            NumberState::Absent
+        }
+    }
+
+    /// Return the value of a string capability if it's present
+    #[must_use]
+    pub fn get_string(&self, name: &str) -> Option<&'a [u8]> {
+        self.strings.get(name).copied()
+    }
+
+    /// Return a copy of a string capability if it's present
+    ///
+    /// The copy doesn't borrow the buffer the entry was parsed from.
+    #[must_use]
+    pub fn string_owned(&self, name: &str) -> Option<Vec<u8>> {
+        self.get_string(name).map(<[u8]>::to_vec)
+    }
+
+    /// Return the value of a string capability as text if it's present
+    ///
+    /// Returns `None` if the capability is absent, the result of the UTF-8
+    /// conversion otherwise. Use `get_string()` for binary data.
+    #[must_use]
+    pub fn get_str(&self, name: &str) -> Option<Result<&'a str, std::str::Utf8Error>> {
+        self.get_string(name).map(str::from_utf8)
+    }
+
+    /// Check whether `sgr` with all attributes off produces `sgr0`
+    ///
+    /// Returns `None` if either capability is absent. Failure to expand `sgr`
+    /// is reported as inconsistency.
+    pub fn sgr_consistent(&self, context: &mut ExpandContext) -> Option<bool> {
+        let sgr = self.get_string("sgr")?;
+        let sgr0 = self.get_string("sgr0")?;
+        let params: [Parameter<'_>; 9] = std::array::from_fn(|_| Parameter::from(0));
+        Some(
+            context
+                .expand(sgr, &params)
+                .is_ok_and(|output| output == sgr0),
+        )
+    }
+
+    /// Convert to an entry that doesn't borrow the buffer
+    #[must_use]
+    pub fn into_owned(self) -> TerminfoOwned {
+        TerminfoOwned::from(&self)
+    }
+
+    /// Return the value of a boolean capability by its long name
+    ///
+    /// For example, `auto_right_margin` is the long name for `am`. Unknown
+    /// long names are treated as absent capabilities.
+    #[must_use]
+    pub fn get_bool_long(&self, long_name: &str) -> bool {
+        short_name(&BOOL_LONG_NAMES, &BOOL_NAMES, long_name).is_some_and(|name| self.get_bool(name))
+    }
+
+    /// Return the value of a numeric capability by its long name
+    ///
+    /// For example, `columns` is the long name for `cols`.
+    #[must_use]
+    pub fn get_number_long(&self, long_name: &str) -> Option<i32> {
+        short_name(&NUMBER_LONG_NAMES, &NUMBER_NAMES, long_name)
+            .and_then(|name| self.get_number(name))
+    }
+
+    /// Return the value of a string capability by its long name
+    ///
+    /// For example, `cursor_address` is the long name for `cup`.
+    #[must_use]
+    pub fn get_string_long(&self, long_name: &str) -> Option<&'a [u8]> {
+        short_name(&STRING_LONG_NAMES, &STRING_NAMES, long_name)
+            .and_then(|name| self.get_string(name))
+    }
+
+    /// Iterate over string capabilities that take parameters
+    ///
+    /// A capability is considered parameterized if it contains a `%p`
+    /// directive.
+    pub fn parameterized_strings(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> + '_ {
+        self.strings
+            .iter()
+            .map(|(&name, &value)| (name, value))
+            .filter(|(_, value)| value.windows(2).any(|window| window == b"%p"))
+    }
+
+    /// Iterate over predefined boolean capabilities missing from the entry
+    ///
+    /// Canceled capabilities are reported as missing.
+    pub fn missing_booleans(&self) -> impl Iterator<Item = &'static str> + '_ {
+        BOOL_NAMES
+            .iter()
+            .copied()
+            .filter(|name| !self.booleans.contains(name))
+    }
+
+    /// Iterate over predefined numeric capabilities missing from the entry
+    ///
+    /// Canceled capabilities are reported as missing.
+    pub fn missing_numbers(&self) -> impl Iterator<Item = &'static str> + '_ {
+        NUMBER_NAMES
+            .iter()
+            .copied()
+            .filter(|name| !self.numbers.contains_key(name))
+    }
+
+    /// Iterate over predefined string capabilities missing from the entry
+    ///
+    /// Canceled capabilities are reported as missing.
+    pub fn missing_strings(&self) -> impl Iterator<Item = &'static str> + '_ {
+        STRING_NAMES
+            .iter()
+            .copied()
+            .filter(|name| !self.strings.contains_key(name))
+    }
+
+    /// Iterate over boolean capabilities in the file order
+    ///
+    /// Predefined capabilities come first in the standard order, followed by
+    /// extended capabilities in the order they appear in the file. Capabilities
+    /// added after parsing come last, sorted by name.
+    pub fn ordered_booleans(&self) -> impl Iterator<Item = &'a str> + '_ {
+        ordered_names(
+            &BOOL_NAMES,
+            &self.extended_booleans,
+            self.booleans.iter().copied(),
+            |name| self.booleans.contains(name),
+        )
+    }
+
+    /// Iterate over numeric capabilities in the file order
+    ///
+    /// The order is the same as in `ordered_booleans()`.
+    pub fn ordered_numbers(&self) -> impl Iterator<Item = (&'a str, i32)> + '_ {
+        ordered_names(
+            &NUMBER_NAMES,
+            &self.extended_numbers,
+            self.numbers.keys().copied(),
+            |name| self.numbers.contains_key(name),
+        )
+        .map(|name| (name, self.numbers[name]))
+    }
+
+    /// Iterate over string capabilities in the file order
+    ///
+    /// The order is the same as in `ordered_booleans()`.
+    pub fn ordered_strings(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> + '_ {
+        ordered_names(
+            &STRING_NAMES,
+            &self.extended_strings,
+            self.strings.keys().copied(),
+            |name| self.strings.contains_key(name),
+        )
+        .map(|name| (name, self.strings[name]))
+    }
+
+    /// Check whether the terminal can redefine colors
+    ///
+    /// This is the `ccc` capability. If it's set, colors can be changed with
+    /// `initc`, which takes the color number and three components. The
+    /// components are hue (0-360), lightness and saturation (0-100) if the
+    /// `hls` capability is set, or red, green and blue (0-1000) otherwise.
+    #[must_use]
+    pub fn can_change_colors(&self) -> bool {
+        self.get_bool("ccc")
+    }
+
+    pub(crate) fn new() -> Self {
         Self {
             booleans: BTreeSet::default(),
             numbers: BTreeMap::default(),
             strings: BTreeMap::default(),
+            term_names: "",
+            canceled_booleans: BTreeSet::default(),
+            canceled_numbers: BTreeSet::default(),
+            canceled_strings: BTreeSet::default(),
+            extended_booleans: vec![],
+            extended_numbers: vec![],
+            extended_strings: vec![],
             number_size: 0,
         }
     }
 
-    fn read_number(&self, reader: &mut Cursor<&'a [u8]>) -> Result<Option<i32>, Error> {
-        let value = if self.number_size == 4 {
+    fn read_number(&self, reader: &mut Cursor<&'a [u8]>) -> Result<i32, Error> {
+        if self.number_size == 4 {
             let mut buffer = [0u8; 4];
             reader.read_exact(&mut buffer)?;
-            i32::from_le_bytes(buffer)
+            Ok(i32::from_le_bytes(buffer))
         } else {
             let mut buffer = [0u8; 2];
             reader.read_exact(&mut buffer)?;
-            i32::from(i16::from_le_bytes(buffer))
-        };
-        if value > 0 { Ok(Some(value)) } else { Ok(None) }
+            Ok(i32::from(i16::from_le_bytes(buffer)))
+        }
     }
 
     /// Parse base capabilities
-    fn parse_base(&mut self, mut reader: &mut Cursor<&'a [u8]>) -> Result<(), Error> {
+    fn parse_base(
+        &mut self,
+        mut reader: &mut Cursor<&'a [u8]>,
+        check_padding: bool,
+    ) -> Result<(), Error> {
         let magic = read_le16(&mut reader)?;
         let name_size = usize::from(read_le16(&mut reader)?);
         let bool_count = usize::from(read_le16(&mut reader)?);
@@ -207,11 +1484,7 @@ impl<'a> Terminfo<'a> {
         let str_count = usize::from(read_le16(&mut reader)?);
         let str_size = usize::from(read_le16(&mut reader)?);
 
-        self.number_size = match magic {
-            val if val == TerminfoMagic::Magic1 as u16 => 2,
-            val if val == TerminfoMagic::Magic2 as u16 => 4,
-            _ => return Err(Error::BadMagic),
-        };
+        self.number_size = TerminfoMagic::number_size(magic)?;
 
         if bool_count > BOOL_NAMES.len()
             || num_count > NUMBER_NAMES.len()
@@ -220,24 +1493,28 @@ impl<'a> Terminfo<'a> {
             return Err(Error::UnsupportedFormat);
         }
 
-        // Skip terminal names/aliases, we are not using them
-        reader.seek_relative(name_size as i64)?;
+        // Names that are not valid UTF-8 are ignored, they are informational.
+        let term_names = read_slice(reader, name_size)?;
+        let term_names = term_names.split(|&c| c == b'\0').next().unwrap_or_default();
+        self.term_names = str::from_utf8(term_names).unwrap_or_default();
 
         for name in BOOL_NAMES.iter().take(bool_count) {
-            let value = read_u8(&mut reader)?;
-            match value {
-                0 => continue,
-                1 => {}
+            match read_u8(&mut reader)? {
+                0 => {}
+                1 => _ = self.booleans.insert(*name),
+                CANCELED_BOOLEAN => _ = self.canceled_booleans.insert(*name),
                 value => return Err(Error::InvalidBooleanValue(value)),
             }
-            self.booleans.insert(*name);
         }
 
-        align_cursor(reader)?;
+        align_cursor(reader, check_padding)?;
 
+        // Negative values are absent, canceled or invalid, zero is a valid value.
         for name in NUMBER_NAMES.iter().take(num_count) {
-            if let Some(number) = self.read_number(reader)? {
-                self.numbers.insert(*name, number);
+            match self.read_number(reader)? {
+                CANCELED_ENTRY => _ = self.canceled_numbers.insert(*name),
+                number if number >= 0 => _ = self.numbers.insert(*name, number),
+                _ => {}
             }
         }
 
@@ -248,6 +1525,9 @@ impl<'a> Terminfo<'a> {
 
         for name in STRING_NAMES.iter().take(str_count) {
             let offset = read_le16(&mut str_offsets_reader)?;
+            if is_canceled_offset(offset) {
+                self.canceled_strings.insert(*name);
+            }
             let Some(offset) = check_offset(offset) else {
                 continue;
             };
@@ -259,8 +1539,12 @@ impl<'a> Terminfo<'a> {
     }
 
     /// Parse extended capabilities
-    fn parse_extended(&mut self, mut reader: &mut Cursor<&'a [u8]>) -> Result<(), Error> {
-        align_cursor(reader)?;
+    fn parse_extended(
+        &mut self,
+        mut reader: &mut Cursor<&'a [u8]>,
+        check_padding: bool,
+    ) -> Result<(), Error> {
+        align_cursor(reader, check_padding)?;
 
         let bool_count = usize::from(read_le16(&mut reader)?);
         let num_count = usize::from(read_le16(&mut reader)?);
@@ -270,7 +1554,7 @@ impl<'a> Terminfo<'a> {
 
         let bools = read_slice(reader, bool_count)?;
         let mut bools_reader = Cursor::new(bools);
-        align_cursor(reader)?;
+        align_cursor(reader, check_padding)?;
 
         let nums = read_slice(reader, self.number_size * num_count)?;
         let mut nums_reader = Cursor::new(nums);
@@ -284,71 +1568,81 @@ impl<'a> Terminfo<'a> {
 
         let str_table = read_slice(reader, str_limit)?;
 
-        let mut names_base = 0;
-        loop {
-            let Ok(offset) = read_le16(&mut strs_reader) else {
-                break;
-            };
+        // Loops are bounded by the counts, anything after the tables is ignored.
+        let mut names_base: usize = 0;
+        for _ in 0..str_count {
+            let offset = read_le16(&mut strs_reader)?;
             let Some(offset) = check_offset(offset) else {
                 continue;
             };
-            names_base += get_string(str_table, offset)?.len() + 1;
+            // Strings may overlap, so the sum is not bounded by the table size.
+            let Some(next_base) = names_base.checked_add(get_string(str_table, offset)?.len() + 1)
+            else {
+                return Err(Error::UnsupportedFormat);
+            };
+            names_base = next_base;
         }
 
         let Some(names_table) = &str_table.get(names_base..) else {
             return Err(Error::UnsupportedFormat);
         };
 
-        loop {
-            let Ok(value) = read_u8(&mut bools_reader) else {
-                break;
-            };
+        for _ in 0..bool_count {
+            let value = read_u8(&mut bools_reader)?;
             let Ok(name_offset) = read_le16(&mut names_reader) else {
                 return Err(Error::UnsupportedFormat);
             };
-            match value {
+            let booleans = match value {
                 0 => continue,
-                1 => {}
+                1 => &mut self.booleans,
+                CANCELED_BOOLEAN => &mut self.canceled_booleans,
                 value => return Err(Error::InvalidBooleanValue(value)),
-            }
+            };
             let Some(name_offset) = check_offset(name_offset) else {
                 return Err(Error::UnsupportedFormat);
             };
-            let name = get_string(names_table, name_offset)?;
-            self.booleans.insert(str::from_utf8(name)?);
+            let name = get_name(names_table, name_offset)?;
+            booleans.insert(name);
+            self.extended_booleans.push(name);
         }
 
-        loop {
-            let Ok(value) = self.read_number(&mut nums_reader) else {
-                break;
-            };
+        for _ in 0..num_count {
+            let value = self.read_number(&mut nums_reader)?;
             let Ok(name_offset) = read_le16(&mut names_reader) else {
                 return Err(Error::UnsupportedFormat);
             };
-            let Some(value) = value else {
+            if value < 0 && value != CANCELED_ENTRY {
                 continue;
-            };
+            }
             let Some(name_offset) = check_offset(name_offset) else {
                 return Err(Error::UnsupportedFormat);
             };
-            let name = get_string(names_table, name_offset)?;
-            self.numbers.insert(str::from_utf8(name)?, value);
+            let name = get_name(names_table, name_offset)?;
+            self.extended_numbers.push(name);
+            if value >= 0 {
+                self.numbers.insert(name, value);
+            } else {
+                self.canceled_numbers.insert(name);
+            }
         }
 
         strs_reader.set_position(0);
-        loop {
-            let Ok(str_offset) = read_le16(&mut strs_reader) else {
-                break;
-            };
+        for _ in 0..str_count {
+            let str_offset = read_le16(&mut strs_reader)?;
             let Ok(name_offset) = read_le16(&mut names_reader) else {
                 return Err(Error::UnsupportedFormat);
             };
-            if let (Some(str_offset), Some(name_offset)) =
-                (check_offset(str_offset), check_offset(name_offset))
-            {
+            let Some(name_offset) = check_offset(name_offset) else {
+                continue;
+            };
+            if is_canceled_offset(str_offset) {
+                self.canceled_strings
+                    .insert(get_name(names_table, name_offset)?);
+            } else if let Some(str_offset) = check_offset(str_offset) {
                 let value = get_string(str_table, str_offset)?;
-                let name = get_string(names_table, name_offset)?;
-                self.strings.insert(str::from_utf8(name)?, value);
+                let name = get_name(names_table, name_offset)?;
+                self.strings.insert(name, value);
+                self.extended_strings.push(name);
             }
         }
 
@@ -356,6 +1650,136 @@ impl<'a> Terminfo<'a> {
     }
 }
 
+/// Parsed terminfo entry that doesn't borrow the buffer
+///
+/// The fields have the same meaning as in `Terminfo`. String values are
+/// reference counted, so that they can be shared between entries. Names of
+/// predefined capabilities are borrowed from the static tables, only extended
+/// capability names are allocated.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TerminfoOwned {
+    pub booleans: BTreeSet<Cow<'static, str>>,
+    pub numbers: BTreeMap<Cow<'static, str>, i32>,
+    pub strings: BTreeMap<Cow<'static, str>, Arc<[u8]>>,
+}
+
+impl TerminfoOwned {
+    /// Return the string capability compiled for repeated expansion
+    ///
+    /// Same as `Terminfo::cap()`.
+    #[must_use]
+    pub fn cap(&self, name: &str) -> Option<Capability<'_>> {
+        self.strings.get(name).map(|value| Capability::new(value))
+    }
+
+    /// Return a normalized copy of the entry for comparison
+    ///
+    /// Obsolete predefined capabilities (those with names starting with `OT`
+    /// and `UT`) are dropped, as they are mostly kept for compatibility with
+    /// termcap and vary between sources. Capabilities are already sorted by
+    /// name, so entries describing the same terminal compare equal.
+    #[must_use]
+    pub fn canonicalize(&self) -> Self {
+        let keep = |names: &[&str], name: &str| !(is_obsolete(name) && names.contains(&name));
+        Self {
+            booleans: self
+                .booleans
+                .iter()
+                .filter(|name| keep(&BOOL_NAMES, name))
+                .cloned()
+                .collect(),
+            numbers: self
+                .numbers
+                .iter()
+                .filter(|(name, _)| keep(&NUMBER_NAMES, name))
+                .map(|(name, &value)| (name.clone(), value))
+                .collect(),
+            strings: self
+                .strings
+                .iter()
+                .filter(|(name, _)| keep(&STRING_NAMES, name))
+                .map(|(name, value)| (name.clone(), Arc::clone(value)))
+                .collect(),
+        }
+    }
+
+    fn with_interner(terminfo: &Terminfo<'_>, interner: &mut Interner) -> Self {
+        Self {
+            strings: terminfo
+                .strings
+                .iter()
+                .map(|(&name, &value)| (owned_name(&STRING_NAMES, name), interner.intern(value)))
+                .collect(),
+            ..Self::without_strings(terminfo)
+        }
+    }
+
+    fn without_strings(terminfo: &Terminfo<'_>) -> Self {
+        Self {
+            booleans: terminfo
+                .booleans
+                .iter()
+                .map(|&name| owned_name(&BOOL_NAMES, name))
+                .collect(),
+            numbers: terminfo
+                .numbers
+                .iter()
+                .map(|(&name, &value)| (owned_name(&NUMBER_NAMES, name), value))
+                .collect(),
+            strings: BTreeMap::default(),
+        }
+    }
+}
+
+impl From<&Terminfo<'_>> for TerminfoOwned {
+    fn from(terminfo: &Terminfo<'_>) -> Self {
+        Self {
+            strings: terminfo
+                .strings
+                .iter()
+                .map(|(&name, &value)| (owned_name(&STRING_NAMES, name), Arc::from(value)))
+                .collect(),
+            ..Self::without_strings(terminfo)
+        }
+    }
+}
+
+/// Storage for string values shared between entries
+#[derive(Debug, Default)]
+pub struct Interner {
+    values: HashSet<Arc<[u8]>>,
+}
+
+impl Interner {
+    /// Create an empty interner
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the shared copy of the value, store it if it's new
+    pub fn intern(&mut self, value: &[u8]) -> Arc<[u8]> {
+        if let Some(shared) = self.values.get(value) {
+            return Arc::clone(shared);
+        }
+        let shared = Arc::from(value);
+        self.values.insert(Arc::clone(&shared));
+        shared
+    }
+
+    /// Return the number of distinct values stored
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Check whether no values are stored
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use collection_literals::collection;
@@ -616,6 +2040,17 @@ mod test {
         );
     }
 
+    #[test]
+    fn associated_parse() {
+        let data_set = DataSet::default();
+        let buffer = make_buffer(&data_set, true);
+        let terminfo = Terminfo::parse(buffer.as_slice()).unwrap();
+        let expected = parse(buffer.as_slice()).unwrap();
+        assert_eq!(terminfo.booleans, expected.booleans);
+        assert_eq!(terminfo.numbers, expected.numbers);
+        assert_eq!(terminfo.strings, expected.strings);
+    }
+
     #[test]
     fn base_32_bit() {
         let data_set = DataSet {
@@ -643,6 +2078,43 @@ mod test {
         );
     }
 
+    #[test]
+    fn zero_number() {
+        let data_set = DataSet {
+            base_numbers: vec![0, -1, 25],
+            ext_numbers: vec![(b"Zero", 0), (b"Absent", -1)],
+            ..Default::default()
+        };
+        let buffer = make_buffer(&data_set, true);
+        let terminfo = parse(buffer.as_slice()).unwrap();
+        assert_eq!(
+            terminfo.numbers,
+            collection!("Zero" => 0, "cols" => 0, "lines" => 25)
+        );
+    }
+
+    #[test]
+    fn large_string_table() {
+        // Offsets above 0x7fff are negative as i16, but not absent or canceled.
+        let base_strings: Vec<StringValue> = (0..60)
+            .map(|n| StringValue::Present(vec![b'A' + n % 26; 1000]))
+            .collect();
+        let data_set = DataSet {
+            base_strings: base_strings.clone(),
+            ..Default::default()
+        };
+        let buffer = make_buffer(&data_set, true);
+        let terminfo = parse(buffer.as_slice()).unwrap();
+        for (name, value) in STRING_NAMES.iter().zip(&base_strings) {
+            assert_eq!(
+                terminfo.strings.get(name).copied(),
+                Option::<&[u8]>::from(value)
+            );
+        }
+        assert_eq!(terminfo.strings.len(), base_strings.len() + 2);
+        assert_eq!(terminfo.strings.get("Final"), Some(&b"Bye".as_slice()));
+    }
+
     #[test]
     fn base_alignment() {
         let data_set = DataSet {
@@ -672,13 +2144,69 @@ mod test {
         );
     }
 
+    #[test]
+    fn malformed_buffers() {
+        // xorshift, so that failures are reproducible
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for number_type in [NumberType::U16, NumberType::U32] {
+            let data_set = DataSet {
+                number_type,
+                ..DataSet::default()
+            };
+            let valid = make_buffer(&data_set, true);
+            for _ in 0..20_000 {
+                let mut buffer = valid.clone();
+                for _ in 0..=random() % 4 {
+                    let index = random() as usize % buffer.len();
+                    buffer[index] = random() as u8;
+                }
+                buffer.truncate(random() as usize % (valid.len() + 1));
+                let _ = parse(&buffer);
+            }
+        }
+        for _ in 0..20_000 {
+            let size = random() as usize % 256;
+            let mut buffer: Vec<u8> = (0..size).map(|_| random() as u8).collect();
+            if size >= 2 {
+                buffer[..2].copy_from_slice(&[0x1e, 0x02]);
+            }
+            let _ = parse(&buffer);
+        }
+    }
+
+    #[test]
+    fn bad_padding() {
+        let data_set = DataSet {
+            base_booleans: vec![1, 0, 0, 0],
+            ..DataSet::default()
+        };
+        let mut buffer = make_buffer(&data_set, true);
+        assert!(parse_strict(&buffer).is_ok());
+        let padding = 12 + data_set.term_name.len() + 1 + data_set.base_booleans.len();
+        assert_eq!(buffer[padding], 0);
+        buffer[padding] = 5;
+        assert!(parse(&buffer).is_ok());
+        assert!(matches!(
+            parse_strict(&buffer).unwrap_err(),
+            Error::BadPadding(5)
+        ));
+    }
+
     #[test]
     fn bad_magic() {
         let data_set = DataSet::default();
         let mut buffer = make_buffer(&data_set, false);
         buffer[1] = 3;
         let terminfo = parse(buffer.as_slice());
-        assert!(matches!(terminfo.unwrap_err(), Error::BadMagic));
+        let error = terminfo.unwrap_err();
+        assert!(matches!(error, Error::BadMagic(0x031a)));
+        assert_eq!(error.to_string(), "Unknown magic number 0x031a");
     }
 
     #[test]
@@ -862,6 +2390,642 @@ mod test {
         );
     }
 
+    #[test]
+    fn capability_kind() {
+        let data_set = DataSet::default();
+        let buffer = make_buffer(&data_set, true);
+        let terminfo = parse(buffer.as_slice()).unwrap();
+        assert_eq!(terminfo.capability_kind("bw"), Some(Kind::Boolean));
+        assert_eq!(terminfo.capability_kind("Primary"), Some(Kind::Boolean));
+        assert_eq!(terminfo.capability_kind("cols"), Some(Kind::Number));
+        assert_eq!(terminfo.capability_kind("Simple"), Some(Kind::Number));
+        assert_eq!(terminfo.capability_kind("bel"), Some(Kind::String));
+        assert_eq!(terminfo.capability_kind("Final"), Some(Kind::String));
+        assert_eq!(terminfo.capability_kind("am"), None);
+        assert_eq!(terminfo.capability_kind("Absent"), None);
+    }
+
+    #[test]
+    fn ordered_caps() {
+        let data_set = DataSet {
+            base_booleans: vec![0, 1, 0, 0, 1],
+            ..DataSet::default()
+        };
+        let buffer = make_buffer(&data_set, true);
+        let mut terminfo = parse(&buffer).unwrap();
+        terminfo.booleans.insert("Added");
+        terminfo.booleans.insert("bw");
+        assert_eq!(
+            terminfo.ordered_booleans().collect::<Vec<_>>(),
+            [
+                "bw",
+                "am",
+                "xenl",
+                "Primary",
+                "Secondary",
+                "Tertiary",
+                "Added"
+            ]
+        );
+        assert_eq!(
+            terminfo.ordered_numbers().collect::<Vec<_>>(),
+            [
+                ("cols", 80),
+                ("lines", 25),
+                ("pb", 5),
+                ("Simple", 1100),
+                ("Overflowing", 7)
+            ]
+        );
+        assert_eq!(
+            terminfo.ordered_strings().collect::<Vec<_>>(),
+            [
+                ("bel", b"Hello".as_slice()),
+                ("csr", b"World!"),
+                ("Present", b"Indeed"),
+                ("Final", b"Bye"),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_caps() {
+        let mut terminfo = Terminfo::new();
+        assert!(terminfo.missing_booleans().eq(BOOL_NAMES));
+        assert!(terminfo.missing_numbers().eq(NUMBER_NAMES));
+        assert!(terminfo.missing_strings().eq(STRING_NAMES));
+
+        terminfo.booleans.insert("bw");
+        terminfo.booleans.insert("Tc");
+        terminfo.numbers.insert("colors", 8);
+        terminfo.strings.insert("setaf", b"\x1b[3%p1%dm");
+        assert_eq!(terminfo.missing_booleans().next(), Some("am"));
+        assert_eq!(terminfo.missing_booleans().count(), BOOL_NAMES.len() - 1);
+        assert!(!terminfo.missing_numbers().any(|name| name == "colors"));
+        assert!(terminfo.missing_strings().any(|name| name == "setf"));
+        assert!(!terminfo.missing_strings().any(|name| name == "setaf"));
+        assert_eq!(terminfo.missing_strings().count(), STRING_NAMES.len() - 1);
+    }
+
+    #[test]
+    fn known_cap() {
+        assert_eq!(is_known_cap("am"), Some(Kind::Boolean));
+        assert_eq!(is_known_cap("OTbs"), Some(Kind::Boolean));
+        assert_eq!(is_known_cap("colors"), Some(Kind::Number));
+        assert_eq!(is_known_cap("setaf"), Some(Kind::String));
+        assert_eq!(is_known_cap("setfa"), None);
+        assert_eq!(is_known_cap("Tc"), None);
+        assert_eq!(is_known_cap("auto_right_margin"), None);
+        assert_eq!(is_known_cap(""), None);
+    }
+
+    #[test]
+    fn terminal_names() {
+        let buffer = make_buffer(&DataSet::default(), false);
+        let terminfo = parse(&buffer).unwrap();
+        assert_eq!(terminfo.names().collect::<Vec<_>>(), vec!["myterm"]);
+        assert_eq!(terminfo.description(), None);
+
+        let data_set = DataSet {
+            term_name: b"xterm-256color|xterm-256|xterm with 256 colors".to_vec(),
+            ..DataSet::default()
+        };
+        let buffer = make_buffer(&data_set, true);
+        let terminfo = parse(&buffer).unwrap();
+        assert_eq!(
+            terminfo.names().collect::<Vec<_>>(),
+            vec!["xterm-256color", "xterm-256"]
+        );
+        assert_eq!(terminfo.description(), Some("xterm with 256 colors"));
+        assert_eq!(terminfo.numbers.get("cols"), Some(&80));
+
+        let data_set = DataSet {
+            term_name: b"bad\xff|name".to_vec(),
+            ..DataSet::default()
+        };
+        let buffer = make_buffer(&data_set, true);
+        let terminfo = parse(&buffer).unwrap();
+        assert_eq!(terminfo.names().count(), 0);
+        assert_eq!(terminfo.description(), None);
+    }
+
+    #[test]
+    fn canceled_capabilities() {
+        let mut data_set = DataSet::default();
+        data_set.base_booleans[1] = 0xfe;
+        data_set.ext_booleans.push((b"Gone", 0xfe));
+        data_set.ext_numbers.push((b"Cancelednum", -2));
+        let buffer = make_buffer(&data_set, true);
+        let terminfo = parse(&buffer).unwrap();
+
+        assert!(terminfo.boolean_is_canceled("am"));
+        assert!(terminfo.boolean_is_canceled("Gone"));
+        assert!(!terminfo.boolean_is_canceled("bw"));
+        assert!(!terminfo.boolean_is_canceled("Unset"));
+        assert!(!terminfo.booleans.contains("am"));
+
+        assert!(terminfo.number_is_canceled("it"));
+        assert!(terminfo.number_is_canceled("Cancelednum"));
+        assert!(!terminfo.number_is_canceled("lines"));
+        assert!(!terminfo.number_is_canceled("xmc"));
+        assert!(!terminfo.number_is_canceled("Negative"));
+
+        assert!(terminfo.string_is_canceled("cr"));
+        assert!(terminfo.string_is_canceled("Canceled"));
+        assert!(!terminfo.string_is_canceled("cbt"));
+        assert!(!terminfo.string_is_canceled("Absent"));
+        assert!(!terminfo.string_is_canceled("Present"));
+
+        assert!(!terminfo.number_is_canceled("cr"));
+        assert!(!terminfo.string_is_canceled("it"));
+    }
+
+    #[test]
+    fn long_names() {
+        let buffer = make_buffer(&DataSet::default(), true);
+        let terminfo = parse(&buffer).unwrap();
+        assert!(terminfo.get_bool_long("auto_left_margin"));
+        assert!(terminfo.get_bool_long("eat_newline_glitch"));
+        assert!(!terminfo.get_bool_long("auto_right_margin"));
+        assert!(!terminfo.get_bool_long("Primary"));
+        assert_eq!(terminfo.get_number_long("columns"), Some(80));
+        assert_eq!(terminfo.get_number_long("lines"), Some(25));
+        assert_eq!(terminfo.get_number_long("cols"), None);
+        assert_eq!(terminfo.get_string_long("bell"), Some(b"Hello".as_slice()));
+        assert_eq!(
+            terminfo.get_string_long("change_scroll_region"),
+            Some(b"World!".as_slice())
+        );
+        assert_eq!(terminfo.get_string_long("carriage_return"), None);
+        assert_eq!(terminfo.get_string_long("no_such_capability"), None);
+    }
+
+    #[test]
+    fn long_names_aligned() {
+        assert_eq!(BOOL_LONG_NAMES[BOOL_NAMES.len() - 1], "return_does_clr_eol");
+        assert_eq!(NUMBER_LONG_NAMES[13], "max_colors");
+        assert_eq!(NUMBER_NAMES[13], "colors");
+        assert_eq!(STRING_LONG_NAMES[10], "cursor_address");
+        assert_eq!(STRING_NAMES[10], "cup");
+        assert_eq!(STRING_LONG_NAMES[359], "set_a_foreground");
+        assert_eq!(STRING_NAMES[359], "setaf");
+    }
+
+    #[test]
+    fn merge() {
+        let base_data = DataSet {
+            base_booleans: vec![1, 1, 1],
+            base_numbers: vec![132, 8, 50],
+            base_strings: vec![
+                StringValue::from(b"Back"),
+                StringValue::from(b"Bell"),
+                StringValue::from(b"Return"),
+                StringValue::from(b"Scroll"),
+            ],
+            ..DataSet::default()
+        };
+        let base_buffer = make_buffer(&base_data, true);
+        let base = parse(&base_buffer).unwrap();
+
+        let mut data_set = DataSet::default();
+        data_set.base_booleans[2] = 0xfe;
+        let buffer = make_buffer(&data_set, true);
+        let mut terminfo = parse(&buffer).unwrap();
+        terminfo.merge(&base);
+
+        // bw and xenl are own, am is inherited, xsb is canceled
+        assert!(terminfo.get_bool("bw"));
+        assert!(terminfo.get_bool("am"));
+        assert!(!terminfo.get_bool("xsb"));
+        assert!(terminfo.get_bool("xenl"));
+
+        // cols and lines are own, it is canceled
+        assert_eq!(terminfo.get_number("cols"), Some(80));
+        assert_eq!(terminfo.get_number("it"), None);
+        assert_eq!(terminfo.get_number("lines"), Some(25));
+
+        // cbt is inherited, cr is canceled
+        assert_eq!(terminfo.get_string("cbt"), Some(b"Back".as_slice()));
+        assert_eq!(terminfo.get_string("bel"), Some(b"Hello".as_slice()));
+        assert_eq!(terminfo.get_string("cr"), None);
+        assert_eq!(terminfo.get_string("csr"), Some(b"World!".as_slice()));
+
+        // Extended capabilities are merged too
+        assert_eq!(terminfo.get_string("Present"), Some(b"Indeed".as_slice()));
+    }
+
+    #[test]
+    fn geometry() {
+        let buffer = make_buffer(&DataSet::default(), true);
+        let terminfo = parse(&buffer).unwrap();
+        assert_eq!(terminfo.columns(), Some(80));
+        assert_eq!(terminfo.lines(), Some(25));
+        assert_eq!(terminfo.max_colors(), None);
+
+        let mut base_numbers = vec![-1; 16];
+        base_numbers[13] = 256;
+        let data_set = DataSet {
+            base_numbers,
+            ..DataSet::default()
+        };
+        let buffer = make_buffer(&data_set, true);
+        let terminfo = parse(&buffer).unwrap();
+        assert_eq!(terminfo.columns(), None);
+        assert_eq!(terminfo.lines(), None);
+        assert_eq!(terminfo.max_colors(), Some(256));
+    }
+
+    #[test]
+    fn number_state() {
+        let mut data_set = DataSet::default();
+        data_set.ext_numbers.push((b"Cancelednum", -2));
+        let buffer = make_buffer(&data_set, true);
+        let terminfo = parse(&buffer).unwrap();
+        assert_eq!(terminfo.get_number_raw("cols"), NumberState::Present(80));
+        assert_eq!(terminfo.get_number_raw("lm"), NumberState::Absent);
+        assert_eq!(terminfo.get_number_raw("it"), NumberState::Canceled);
+        assert_eq!(terminfo.get_number_raw("xmc"), NumberState::Absent);
+        assert_eq!(
+            terminfo.get_number_raw("Simple"),
+            NumberState::Present(1100)
+        );
+        assert_eq!(
+            terminfo.get_number_raw("Cancelednum"),
+            NumberState::Canceled
+        );
+        assert_eq!(terminfo.get_number_raw("Negative"), NumberState::Absent);
+        assert_eq!(terminfo.get_number_raw("nonexistent"), NumberState::Absent);
+    }
+
+    #[test]
+    fn string_as_text() {
+        let mut terminfo = Terminfo::new();
+        terminfo.strings.insert("u0", "Grüße".as_bytes());
+        terminfo.strings.insert("u1", b"\xff\x1b[0m");
+        assert_eq!(terminfo.get_str("u0"), Some(Ok("Grüße")));
+        assert!(matches!(terminfo.get_str("u1"), Some(Err(_))));
+        assert_eq!(terminfo.get_str("u2"), None);
+    }
+
+    #[test]
+    fn string_owned() {
+        let buffer = b"\x1b[H\x1b[2J".to_vec();
+        let mut terminfo = Terminfo::new();
+        terminfo.strings.insert("clear", &buffer);
+        let clear = terminfo.string_owned("clear");
+        assert_eq!(terminfo.string_owned("home"), None);
+        drop(terminfo);
+        drop(buffer);
+        assert_eq!(clear.as_deref(), Some(b"\x1b[H\x1b[2J".as_slice()));
+    }
+
+    #[test]
+    fn escape_unescape() {
+        let tests: [(&[u8], &str); 16] = [
+            (b"\x1b[H", "\\E[H"),
+            (b"\n", "\\n"),
+            (b"\r", "\\r"),
+            (b"\t", "\\t"),
+            (b"\x08", "\\b"),
+            (b"\x0c", "\\f"),
+            (b"a b", "a\\sb"),
+            (b"\x80", "\\0"),
+            (b"\x01\x1f", "^A^_"),
+            (b"\x7f", "^?"),
+            (b"\\^,:", "\\\\\\^\\,\\:"),
+            (b"\xff\xc3", "\\377\\303"),
+            (b"\x00", "\\000"),
+            (b"%p1%d", "%p1%d"),
+            (b"", ""),
+            (b"\x1b(B\x1b)0", "\\E(B\\E)0"),
+        ];
+        for (bytes, text) in tests {
+            assert_eq!(escape(bytes), text);
+            if bytes != b"\x00" {
+                assert_eq!(unescape(text), bytes, "{text}");
+            }
+        }
+        assert_eq!(unescape("\\000"), b"\x80");
+        assert_eq!(unescape("\\e\\l\\a"), b"\x1b\n\x07");
+        assert_eq!(unescape("^[^a\\08"), b"\x1b\x01\x808");
+        assert_eq!(unescape("\\1234"), b"S4");
+        assert_eq!(unescape("\\x^"), b"x^");
+        assert_eq!(unescape("\\"), b"\\");
+    }
+
+    #[test]
+    fn sgr_consistent() {
+        let mut context = ExpandContext::new();
+        let mut terminfo = Terminfo::new();
+        assert_eq!(terminfo.sgr_consistent(&mut context), None);
+
+        terminfo.strings.insert(
+            "sgr",
+            b"\x1b[0%?%p1%p6%|%t;1%;%?%p2%t;4%;%?%p1%p3%|%t;7%;%?%p4%t;5%;m%?%p9%t\x0e%e\x0f%;",
+        );
+        assert_eq!(terminfo.sgr_consistent(&mut context), None);
+
+        terminfo.strings.insert("sgr0", b"\x1b[0m\x0f");
+        assert_eq!(terminfo.sgr_consistent(&mut context), Some(true));
+
+        terminfo.strings.insert("sgr0", b"\x1b[m");
+        assert_eq!(terminfo.sgr_consistent(&mut context), Some(false));
+
+        terminfo.strings.insert("sgr", b"%p1%s");
+        assert_eq!(terminfo.sgr_consistent(&mut context), Some(false));
+    }
+
+    #[test]
+    fn typed_accessors() {
+        let buffer = make_buffer(&DataSet::default(), true);
+        let terminfo = parse(&buffer).unwrap();
+        assert!(terminfo.get_bool("Primary"));
+        assert!(!terminfo.get_bool("Unset"));
+        assert_eq!(terminfo.get_number("cols"), Some(80));
+        assert_eq!(terminfo.get_number("it"), None);
+        assert_eq!(terminfo.get_string("bel"), Some(b"Hello".as_slice()));
+        assert_eq!(terminfo.get_string("cr"), None);
+        assert_eq!(terminfo.get_string("cols"), None);
+    }
+
+    #[test]
+    fn size_from_env() {
+        let mut terminfo = Terminfo::new();
+        terminfo.numbers.insert("cols", 80);
+        temp_env::with_vars([("COLUMNS", Some("132")), ("LINES", None)], || {
+            assert_eq!(terminfo.columns_from_env(), Some(132));
+            assert_eq!(terminfo.columns(), Some(80));
+            assert_eq!(terminfo.lines_from_env(), None);
+        });
+        temp_env::with_vars([("COLUMNS", Some("0")), ("LINES", Some("50"))], || {
+            assert_eq!(terminfo.columns_from_env(), Some(80));
+            assert_eq!(terminfo.lines_from_env(), Some(50));
+        });
+        temp_env::with_vars([("COLUMNS", Some("wide")), ("LINES", Some("-1"))], || {
+            assert_eq!(terminfo.columns_from_env(), Some(80));
+            assert_eq!(terminfo.lines_from_env(), None);
+        });
+    }
+
+    #[test]
+    fn validate() {
+        let mut terminfo = Terminfo::new();
+        terminfo.numbers = collection!("cols" => 80, "lines" => 24, "colors" => 8, "pairs" => 64);
+        terminfo.strings = collection!(
+            "cup" => b"\x1b[%i%p1%d;%p2%dH".as_slice(),
+            "setaf" => b"\x1b[3%p1%dm",
+            "scp" => b"%p1%d",
+            "home" => b"\x1b[H",
+        );
+        assert_eq!(terminfo.validate(), vec![]);
+
+        terminfo.numbers = collection!("cols" => 0, "lines" => 24);
+        terminfo.strings.insert("cup", b"\x1b[%p1%dH");
+        terminfo.strings.insert("csr", b"%p1%Y");
+        assert_eq!(
+            terminfo.validate(),
+            vec![
+                ValidationWarning::NonPositiveSize("cols"),
+                ValidationWarning::MissingColors("setaf"),
+                ValidationWarning::MissingPairs("scp"),
+                ValidationWarning::ParameterCount {
+                    name: "cup",
+                    expected: 2,
+                    actual: 1
+                },
+                ValidationWarning::MalformedString("csr"),
+            ]
+        );
+    }
+
+    #[test]
+    fn cap() {
+        let mut terminfo = Terminfo::new();
+        terminfo.strings.insert("cup", b"\x1b[%i%p1%d;%p2%dH");
+        terminfo.strings.insert("bad", b"%Y");
+        let mut context = ExpandContext::new();
+
+        let cup = terminfo.cap("cup").unwrap();
+        assert_eq!(cup.bytes(), b"\x1b[%i%p1%d;%p2%dH");
+        for position in [[0, 0], [23, 79]] {
+            let params = position.map(Parameter::from);
+            assert_eq!(
+                cup.expand(&mut context, &params),
+                context.expand(cup.bytes(), &params)
+            );
+        }
+        let bad = terminfo.cap("bad").unwrap();
+        assert_eq!(
+            bad.expand(&mut context, &[]),
+            Err(expand::Error::UnrecognizedFormatOption('Y'))
+        );
+        assert!(terminfo.cap("home").is_none());
+
+        let owned = terminfo.into_owned();
+        assert_eq!(
+            owned
+                .cap("cup")
+                .unwrap()
+                .expand(&mut context, &[Parameter::from(1)]),
+            Ok(b"\x1b[2;1H".to_vec())
+        );
+        assert!(owned.cap("home").is_none());
+    }
+
+    #[test]
+    fn any_of() {
+        let mut terminfo = Terminfo::new();
+        assert_eq!(terminfo.any_of(&["Tc", "RGB"]), None);
+        terminfo.numbers.insert("RGB", 8);
+        assert_eq!(terminfo.any_of(&["Tc", "RGB"]), Some("RGB"));
+        terminfo
+            .strings
+            .insert("setrgbf", b"\x1b[38;2;%p1%d;%p2%d;%p3%dm");
+        assert_eq!(terminfo.any_of(&["setrgbf", "RGB"]), Some("setrgbf"));
+        terminfo.booleans.insert("Tc");
+        assert_eq!(terminfo.any_of(&["Tc", "RGB"]), Some("Tc"));
+        assert_eq!(terminfo.any_of(&[]), None);
+    }
+
+    #[test]
+    fn parameterized_strings() {
+        let mut terminfo = Terminfo::new();
+        terminfo.strings = collection!(
+            "bel" => b"\x07".as_slice(),
+            "cup" => b"\x1b[%i%p1%d;%p2%dH",
+            "home" => b"\x1b[H",
+            "Smulx" => b"\x1b[4:%p1%dm",
+        );
+        assert_eq!(
+            terminfo.parameterized_strings().collect::<Vec<_>>(),
+            vec![
+                ("Smulx", b"\x1b[4:%p1%dm".as_slice()),
+                ("cup", b"\x1b[%i%p1%d;%p2%dH"),
+            ]
+        );
+    }
+
+    #[test]
+    fn can_change_colors() {
+        let mut terminfo = Terminfo::new();
+        assert!(!terminfo.can_change_colors());
+        terminfo.booleans.insert("ccc");
+        assert!(terminfo.can_change_colors());
+    }
+
+    #[test]
+    fn into_owned() {
+        let owned = {
+            let buffer = make_buffer(&DataSet::default(), true);
+            parse(&buffer).unwrap().into_owned()
+        };
+        assert_eq!(
+            owned.booleans,
+            BTreeSet::from(["Primary", "Secondary", "Tertiary", "bw", "xenl"].map(Cow::from))
+        );
+        assert_eq!(owned.numbers.get("Simple"), Some(&1100));
+        assert_eq!(&owned.strings["Final"][..], b"Bye");
+        assert_eq!(owned.strings.get("Absent"), None);
+    }
+
+    #[test]
+    fn owned_names_borrowed() {
+        let buffer = make_buffer(&DataSet::default(), true);
+        let owned = parse_owned(&buffer).unwrap();
+        let is_borrowed = |name: &Cow<'static, str>| matches!(name, Cow::Borrowed(_));
+        for name in &owned.booleans {
+            assert_eq!(is_borrowed(name), BOOL_NAMES.contains(&&**name), "{name}");
+        }
+        for name in owned.numbers.keys() {
+            assert_eq!(is_borrowed(name), NUMBER_NAMES.contains(&&**name), "{name}");
+        }
+        for name in owned.strings.keys() {
+            assert_eq!(is_borrowed(name), STRING_NAMES.contains(&&**name), "{name}");
+        }
+        assert!(is_borrowed(owned.booleans.get("bw").unwrap()));
+        assert!(!is_borrowed(owned.booleans.get("Primary").unwrap()));
+    }
+
+    #[test]
+    fn owned_outlives_buffer() {
+        let buffer = make_buffer(&DataSet::default(), true);
+        let owned = parse_owned(&buffer).unwrap();
+        let expected = parse(&buffer).unwrap().into_owned();
+        drop(buffer);
+
+        let cloned = owned.clone();
+        drop(owned);
+        assert_eq!(cloned, expected);
+        assert!(cloned.booleans.contains("Tertiary"));
+        assert_eq!(cloned.numbers.get("Simple"), Some(&1100));
+        assert_eq!(&cloned.strings["Present"][..], b"Indeed");
+    }
+
+    #[test]
+    fn from_reader() {
+        let buffer = make_buffer(&DataSet::default(), true);
+        let owned = parse_reader(buffer.as_slice()).unwrap();
+        assert_eq!(owned, parse(&buffer).unwrap().into_owned());
+
+        let truncated = &buffer[..buffer.len() / 4];
+        assert!(matches!(
+            parse_reader(Cursor::new(truncated)),
+            Err(Error::UnsupportedFormat)
+        ));
+    }
+
+    #[test]
+    fn canonicalize() {
+        let mut terminfo1 = Terminfo::new();
+        terminfo1.booleans = collection!("am", "OTbs", "OTpt", "Ext");
+        terminfo1.numbers = collection!("cols" => 80, "OTkn" => 12);
+        terminfo1.strings = collection!("bel" => b"\x07".as_slice(), "OTnl" => b"\n");
+        let mut terminfo2 = Terminfo::new();
+        terminfo2.booleans = collection!("am", "Ext");
+        terminfo2.numbers = collection!("cols" => 80, "UTug" => 1);
+        terminfo2.strings = collection!("bel" => b"\x07".as_slice(), "OTExt" => b"x");
+        let owned1 = terminfo1.into_owned();
+        let owned2 = terminfo2.into_owned();
+        assert_ne!(owned1, owned2);
+        assert_ne!(owned1.canonicalize(), owned2.canonicalize());
+
+        let mut owned2 = owned2;
+        owned2.strings.remove("OTExt");
+        assert_ne!(owned1, owned2);
+        assert_eq!(owned1.canonicalize(), owned2.canonicalize());
+        assert_eq!(owned2.canonicalize().canonicalize(), owned2.canonicalize());
+        assert_eq!(owned1.canonicalize().booleans.len(), 2);
+    }
+
+    #[test]
+    fn interned() {
+        let mut interner = Interner::new();
+        let buffer1 = make_buffer(&DataSet::default(), true);
+        let data_set = DataSet {
+            base_strings: vec![StringValue::from(b"Bye"), StringValue::from(b"Hello")],
+            ..DataSet::default()
+        };
+        let buffer2 = make_buffer(&data_set, true);
+        let entry1 = parse_interned(&buffer1, &mut interner).unwrap();
+        let entry2 = parse_interned(&buffer2, &mut interner).unwrap();
+
+        assert_eq!(entry1, parse(&buffer1).unwrap().into_owned());
+        assert_eq!(entry2, parse(&buffer2).unwrap().into_owned());
+        assert!(Arc::ptr_eq(&entry1.strings["bel"], &entry2.strings["bel"]));
+        assert!(Arc::ptr_eq(
+            &entry1.strings["Final"],
+            &entry2.strings["cbt"]
+        ));
+        assert!(!Arc::ptr_eq(&entry1.strings["bel"], &entry1.strings["csr"]));
+        // Hello, World!, Indeed, Bye
+        assert_eq!(interner.len(), 4);
+    }
+
+    #[test]
+    fn owned_from_env() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let temp_dir = temp_dir.path();
+        let leaf_dir = temp_dir.join("m");
+        std::fs::create_dir(&leaf_dir).unwrap();
+        let buffer = make_buffer(&DataSet::default(), true);
+        std::fs::write(leaf_dir.join("myterm-1005"), &buffer).unwrap();
+
+        temp_env::with_vars(
+            [
+                ("TERM", Some("myterm-1005")),
+                ("TERMINFO", Some(temp_dir.to_str().unwrap())),
+                ("TERMINFO_DIRS", None),
+            ],
+            || {
+                let owned = TerminfoOwned::from_env().unwrap();
+                let terminfo = parse(&buffer).unwrap();
+                assert_eq!(owned, TerminfoOwned::from(&terminfo));
+                assert!(owned.booleans.contains("Primary"));
+                assert_eq!(owned.numbers.get("cols"), Some(&80));
+                assert_eq!(&owned.strings["bel"][..], b"Hello");
+            },
+        );
+        temp_env::with_vars(
+            [
+                ("TERM", Some("myterm-1005-missing")),
+                ("TERMINFO", Some(temp_dir.to_str().unwrap())),
+                ("TERMINFO_DIRS", None),
+            ],
+            || {
+                assert!(matches!(
+                    TerminfoOwned::from_env(),
+                    Err(crate::Error::Locate(_))
+                ));
+            },
+        );
+        temp_env::with_var_unset("TERM", || {
+            assert!(matches!(
+                TerminfoOwned::from_env(),
+                Err(crate::Error::Environment(_))
+            ));
+        });
+    }
+
     #[test]
     fn extended_unterminated_string() {
         let data_set = DataSet::default();
@@ -872,6 +3036,82 @@ mod test {
         assert!(matches!(terminfo.unwrap_err(), Error::UnterminatedString));
     }
 
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("myterm");
+        let buffer = make_buffer(&DataSet::default(), true);
+        std::fs::write(&path, &buffer).unwrap();
+
+        let mapped = parse_mmap(&path).unwrap();
+        assert_eq!(mapped.as_bytes(), buffer);
+        assert_eq!(mapped.terminfo().strings, parse(&buffer).unwrap().strings);
+
+        std::fs::write(&path, b"bad").unwrap();
+        assert!(matches!(
+            parse_mmap(&path),
+            Err(crate::Error::Parse(Error::IO(_)))
+        ));
+        assert!(matches!(
+            parse_mmap(temp_dir.path().join("missing")),
+            Err(crate::Error::Read(_))
+        ));
+    }
+
+    #[test]
+    fn empty_strings() {
+        let data_set = DataSet {
+            base_strings: vec![
+                StringValue::Present(vec![]),
+                StringValue::Present(b"x".to_vec()),
+            ],
+            ext_strings: vec![(b"Empty", StringValue::Present(vec![]))],
+            ..Default::default()
+        };
+        let buffer = make_buffer(&data_set, true);
+        let terminfo = parse(&buffer).unwrap();
+        assert_eq!(terminfo.get_string("cbt"), Some(b"".as_slice()));
+        assert_eq!(terminfo.get_string("bel"), Some(b"x".as_slice()));
+        assert_eq!(terminfo.get_string("Empty"), Some(b"".as_slice()));
+        assert_eq!(terminfo.get_string("cr"), None);
+
+        let written = terminfo.to_bytes().unwrap();
+        assert_eq!(parse(&written).unwrap().strings, terminfo.strings);
+    }
+
+    #[test]
+    fn extended_trailing_bytes() {
+        let data_set = DataSet {
+            ext_booleans: vec![(b"True", 1)],
+            ext_numbers: vec![(b"Num", 42)],
+            ..Default::default()
+        };
+        let buffer = make_buffer(&data_set, true);
+        let expected = parse(&buffer).unwrap();
+        let mut padded = buffer.clone();
+        padded.extend_from_slice(b"\x01\x00\x02\x00garbage\x00\xff\xff");
+        let terminfo = parse(&padded).unwrap();
+        assert_eq!(terminfo.booleans, expected.booleans);
+        assert_eq!(terminfo.numbers, expected.numbers);
+        assert_eq!(terminfo.strings, expected.strings);
+        assert_eq!(terminfo.numbers.get("Num"), Some(&42));
+    }
+
+    #[test]
+    fn extended_name_mid_string() {
+        let mut terminfo = Terminfo::new();
+        terminfo.booleans.insert("Ext");
+        let mut buffer = terminfo.to_bytes().unwrap();
+        let buffer_size = buffer.len();
+        // The name offset precedes the string table "Ext\0".
+        assert_eq!(buffer[buffer_size - 6..], *b"\0\0Ext\0");
+        assert_eq!(parse(&buffer).unwrap().booleans, terminfo.booleans);
+        buffer[buffer_size - 6] = 1;
+        let terminfo = parse(buffer.as_slice());
+        assert!(matches!(terminfo.unwrap_err(), Error::UnsupportedFormat));
+    }
+
     #[test]
     fn extended_bad_boolean() {
         let data_set = DataSet {
@@ -0,0 +1,20 @@
+// Copyright 2025 Pavel Roskin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Common types for glob import
+//!
+//! ```
+//! use terminfo_lean::prelude::*;
+//! ```
+
+pub use crate::{
+    Error,
+    expand::{ExpandContext, Parameter},
+    locate::locate,
+    parse::Terminfo,
+};
@@ -0,0 +1,709 @@
+// Copyright 2025 Pavel Roskin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! High-level output generation for a terminal
+
+use std::ops::{BitOr, BitOrAssign};
+
+use crate::{
+    expand::{self, ExpandContext, Parameter},
+    locate,
+    parse::{Terminfo, TerminfoOwned},
+};
+
+/// Errors reported when generating output for a terminal
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The terminal doesn't have the required capability
+    #[error("Missing capability {0}")]
+    MissingCapability(String),
+    /// The terminal doesn't support the requested mode
+    #[error("Unsupported mode {0}")]
+    UnsupportedMode(u16),
+    /// The color is not below the `colors` capability
+    #[error("Color {0} out of range")]
+    ColorOutOfRange(u8),
+    /// Capability expansion failed
+    #[error("Expansion error")]
+    Expand(#[from] expand::Error),
+}
+
+/// Set of optional terminal features
+///
+/// The features are detected from the terminfo entry by `Terminal::features()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FeatureSet(u8);
+
+impl FeatureSet {
+    /// 24-bit colors (`RGB`, `Tc` or `setrgbf`)
+    pub const TRUECOLOR: Self = Self(1 << 0);
+    /// Underline styles (`Smulx` or `Su`)
+    pub const STYLED_UNDERLINE: Self = Self(1 << 1);
+    /// Italic text (`sitm`)
+    pub const ITALICS: Self = Self(1 << 2);
+    /// Crossed out text (`smxx`)
+    pub const STRIKETHROUGH: Self = Self(1 << 3);
+    /// Bracketed paste mode (`BE`)
+    pub const BRACKETED_PASTE: Self = Self(1 << 4);
+    /// Mouse reporting (`kmous` or `XM`)
+    pub const MOUSE: Self = Self(1 << 5);
+
+    /// Return an empty set
+    #[must_use]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Check whether the set is empty
+    #[must_use]
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Check whether all features from `other` are in the set
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Add features from `other` to the set
+    pub const fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitOr for FeatureSet {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for FeatureSet {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.insert(rhs);
+    }
+}
+
+/// Text attributes set by `Terminal::sgr()`
+///
+/// The fields are in the order of the `sgr` parameters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct SgrAttrs {
+    pub standout: bool,
+    pub underline: bool,
+    pub reverse: bool,
+    pub blink: bool,
+    pub dim: bool,
+    pub bold: bool,
+    pub invis: bool,
+    pub protect: bool,
+    pub altcharset: bool,
+}
+
+impl SgrAttrs {
+    /// Return the attributes with the capabilities that set them separately
+    const fn with_caps(self) -> [(bool, &'static str); 9] {
+        [
+            (self.standout, "smso"),
+            (self.underline, "smul"),
+            (self.reverse, "rev"),
+            (self.blink, "blink"),
+            (self.dim, "dim"),
+            (self.bold, "bold"),
+            (self.invis, "invis"),
+            (self.protect, "prot"),
+            (self.altcharset, "smacs"),
+        ]
+    }
+}
+
+/// Terminfo entry, either borrowing the database buffer or owning its data
+enum Entry<'a> {
+    Borrowed(Terminfo<'a>),
+    Owned(TerminfoOwned),
+}
+
+impl Entry<'_> {
+    fn get_bool(&self, name: &str) -> bool {
+        match self {
+            Self::Borrowed(terminfo) => terminfo.get_bool(name),
+            Self::Owned(terminfo) => terminfo.booleans.contains(name),
+        }
+    }
+
+    fn get_number(&self, name: &str) -> Option<i32> {
+        match self {
+            Self::Borrowed(terminfo) => terminfo.get_number(name),
+            Self::Owned(terminfo) => terminfo.numbers.get(name).copied(),
+        }
+    }
+
+    fn get_string(&self, name: &str) -> Option<&[u8]> {
+        match self {
+            Self::Borrowed(terminfo) => terminfo.get_string(name),
+            Self::Owned(terminfo) => terminfo.strings.get(name).map(|value| &**value),
+        }
+    }
+}
+
+/// Terminal described by a terminfo entry
+///
+/// The expansion context is kept together with the entry, so that static
+/// variables persist between expansions for the same terminal.
+pub struct Terminal<'a> {
+    entry: Entry<'a>,
+    context: ExpandContext,
+}
+
+impl<'a> Terminal<'a> {
+    /// Create a terminal from a parsed terminfo entry
+    ///
+    /// The padding character is taken from the `pad` capability, XON/XOFF flow
+    /// control is assumed if the `xon` capability is present.
+    #[must_use]
+    pub fn new(terminfo: Terminfo<'a>) -> Self {
+        Self::with_entry(Entry::Borrowed(terminfo))
+    }
+
+    /// Create a terminal for the current `TERM`
+    ///
+    /// The terminfo entry is loaded by `locate::load_current()` and kept by
+    /// the terminal, so the database is only read once.
+    pub fn from_env() -> Result<Self, crate::Error> {
+        Ok(Self::with_entry(Entry::Owned(locate::load_current()?)))
+    }
+
+    /// Expand a string capability by name
+    ///
+    /// This is the equivalent of `tput`. The expansion context of the terminal
+    /// is used, so static variables are kept between calls.
+    pub fn cap(&mut self, name: &str, params: &[Parameter]) -> Result<Vec<u8>, crate::Error> {
+        let Some(cap) = self.entry.get_string(name) else {
+            return Err(Error::MissingCapability(name.to_string()).into());
+        };
+        Ok(self.context.expand(cap, params)?)
+    }
+
+    /// Set the output speed to enable padding
+    ///
+    /// Padding is not done if the speed is below `pb`, the lowest speed that
+    /// needs padding. See `ExpandContext::set_baud_rate()` for details.
+    pub fn set_baud_rate(&mut self, baud_rate: Option<u32>) {
+        let min_baud_rate = self.entry.get_number("pb").unwrap_or(0);
+        let baud_rate = baud_rate.filter(|&rate| i64::from(rate) >= i64::from(min_baud_rate));
+        self.context.set_baud_rate(baud_rate);
+    }
+
+    /// Check whether a boolean capability is present in the entry
+    #[must_use]
+    pub fn get_bool(&self, name: &str) -> bool {
+        self.entry.get_bool(name)
+    }
+
+    /// Return the value of a numeric capability if it's present
+    #[must_use]
+    pub fn get_number(&self, name: &str) -> Option<i32> {
+        self.entry.get_number(name)
+    }
+
+    /// Return the value of a string capability if it's present
+    #[must_use]
+    pub fn get_string(&self, name: &str) -> Option<&[u8]> {
+        self.entry.get_string(name)
+    }
+
+    /// Detect optional features supported by the terminal
+    ///
+    /// Both the standard capabilities and the common extended ones are checked.
+    #[must_use]
+    pub fn features(&self) -> FeatureSet {
+        let entry = &self.entry;
+        let checks = [
+            (FeatureSet::TRUECOLOR, &["RGB", "Tc", "setrgbf"][..]),
+            (FeatureSet::STYLED_UNDERLINE, &["Smulx", "Su"]),
+            (FeatureSet::ITALICS, &["sitm"]),
+            (FeatureSet::STRIKETHROUGH, &["smxx"]),
+            (FeatureSet::BRACKETED_PASTE, &["BE"]),
+            (FeatureSet::MOUSE, &["kmous", "XM"]),
+        ];
+        let mut features = FeatureSet::empty();
+        for (feature, names) in checks {
+            if names
+                .iter()
+                .any(|&name| entry.get_bool(name) || entry.get_string(name).is_some())
+            {
+                features |= feature;
+            }
+        }
+        features
+    }
+
+    /// Set the scrolling region and move the cursor home
+    ///
+    /// The region from `top` to `bottom` (inclusive, zero-based) is set using
+    /// `csr`. The cursor position is undefined after `csr`, so it's moved to
+    /// the home position using `home`, or `cup` to row and column 0 if `home`
+    /// is absent.
+    pub fn set_region_home(&mut self, top: u16, bottom: u16) -> Result<Vec<u8>, Error> {
+        let Some(csr) = self.entry.get_string("csr") else {
+            return Err(Error::MissingCapability("csr".to_string()));
+        };
+        let params = [top, bottom].map(Parameter::from);
+        let mut output = self.context.expand(csr, &params)?;
+        if let Some(home) = self.entry.get_string("home") {
+            self.context.expand_append(home, &[], &mut output)?;
+        } else if let Some(cup) = self.entry.get_string("cup") {
+            let params = [0, 0].map(Parameter::from);
+            self.context.expand_append(cup, &params, &mut output)?;
+        } else {
+            return Err(Error::MissingCapability("home".to_string()));
+        }
+        Ok(output)
+    }
+
+    /// Erase in line using the `el` capability
+    ///
+    /// The mode has the same meaning as in the ECMA-48 `EL` control sequence:
+    ///
+    /// * 0 - erase from the cursor to the end of the line
+    /// * 1 - erase from the start of the line to the cursor
+    /// * 2 - erase the whole line
+    ///
+    /// If `el` takes a parameter, it's expanded with the mode. Otherwise, only
+    /// mode 0 is supported.
+    pub fn erase_in_line(&mut self, mode: u16) -> Result<Vec<u8>, Error> {
+        self.erase("el", mode)
+    }
+
+    /// Erase in display using the `ed` capability
+    ///
+    /// The mode has the same meaning as in the ECMA-48 `ED` control sequence:
+    ///
+    /// * 0 - erase from the cursor to the end of the screen
+    /// * 1 - erase from the start of the screen to the cursor
+    /// * 2 - erase the whole screen
+    ///
+    /// If `ed` takes a parameter, it's expanded with the mode. Otherwise, only
+    /// mode 0 is supported.
+    pub fn erase_in_display(&mut self, mode: u16) -> Result<Vec<u8>, Error> {
+        self.erase("ed", mode)
+    }
+
+    /// Redefine a color using the `initc` capability
+    ///
+    /// The components are red, green and blue in the range 0-1000, unless the
+    /// terminal has the `hls` capability, in which case they are hue (0-360),
+    /// lightness and saturation (0-100). The components are passed to `initc`
+    /// as is, no conversion is done.
+    ///
+    /// Fails if the terminal cannot change colors, as indicated by the `ccc`
+    /// capability.
+    pub fn init_color(&mut self, color: i32, components: [i32; 3]) -> Result<Vec<u8>, Error> {
+        if !self.entry.get_bool("ccc") {
+            return Err(Error::MissingCapability("ccc".to_string()));
+        }
+        let Some(cap) = self.entry.get_string("initc") else {
+            return Err(Error::MissingCapability("initc".to_string()));
+        };
+        let [c1, c2, c3] = components;
+        let params = [color, c1, c2, c3].map(Parameter::from);
+        Ok(self.context.expand(cap, &params)?)
+    }
+
+    /// Set text attributes, turning off all others
+    ///
+    /// `sgr` is used if present. Otherwise, the attributes are reset by `sgr0`
+    /// and the requested ones are set by their own capabilities, such as
+    /// `bold` and `smul`.
+    pub fn sgr(&mut self, attrs: SgrAttrs) -> Result<Vec<u8>, Error> {
+        if let Some(sgr) = self.entry.get_string("sgr") {
+            let params = attrs.with_caps().map(|(set, _)| Parameter::from(set));
+            return Ok(self.context.expand(sgr, &params)?);
+        }
+        let Some(sgr0) = self.entry.get_string("sgr0") else {
+            return Err(Error::MissingCapability("sgr0".to_string()));
+        };
+        let mut output = self.context.expand(sgr0, &[])?;
+        for (set, name) in attrs.with_caps() {
+            if !set {
+                continue;
+            }
+            let Some(cap) = self.entry.get_string(name) else {
+                return Err(Error::MissingCapability(name.to_string()));
+            };
+            self.context.expand_append(cap, &[], &mut output)?;
+        }
+        Ok(output)
+    }
+
+    /// Set the foreground color
+    ///
+    /// Colors are numbered like in ANSI: 0 is black, 1 is red, 4 is blue and
+    /// so on. `setaf` is used if present, otherwise `setf` with red and blue
+    /// swapped, as it numbers colors differently.
+    ///
+    /// Fails if the color is not below the `colors` capability.
+    pub fn set_fg(&mut self, color: u8) -> Result<Vec<u8>, Error> {
+        self.set_color(color, "setaf", "setf")
+    }
+
+    /// Set the background color
+    ///
+    /// Same as `set_fg()`, but `setab` and `setb` are used.
+    pub fn set_bg(&mut self, color: u8) -> Result<Vec<u8>, Error> {
+        self.set_color(color, "setab", "setb")
+    }
+
+    fn set_color(
+        &mut self,
+        color: u8,
+        ansi_name: &'static str,
+        name: &'static str,
+    ) -> Result<Vec<u8>, Error> {
+        if self
+            .entry
+            .get_number("colors")
+            .is_some_and(|colors| i32::from(color) >= colors)
+        {
+            return Err(Error::ColorOutOfRange(color));
+        }
+        let (cap, color) = if let Some(cap) = self.entry.get_string(ansi_name) {
+            (cap, color)
+        } else if let Some(cap) = self.entry.get_string(name) {
+            // Swap red and blue, keep the other bits.
+            let color = if color < 16 {
+                (color & !0b101) | ((color & 1) << 2) | ((color >> 2) & 1)
+            } else {
+                color
+            };
+            (cap, color)
+        } else {
+            return Err(Error::MissingCapability(ansi_name.to_string()));
+        };
+        Ok(self.context.expand(cap, &[Parameter::from(color)])?)
+    }
+
+    fn with_entry(entry: Entry<'a>) -> Self {
+        let mut context = ExpandContext::new();
+        if let Some(&pad_char) = entry.get_string("pad").and_then(<[u8]>::first) {
+            context.set_pad_char(pad_char);
+        }
+        context.set_xon_xoff(entry.get_bool("xon"));
+        Self { entry, context }
+    }
+
+    fn erase(&mut self, name: &'static str, mode: u16) -> Result<Vec<u8>, Error> {
+        let Some(cap) = self.entry.get_string(name) else {
+            return Err(Error::MissingCapability(name.to_string()));
+        };
+        if expand::required_params(cap)?.count() > 0 {
+            let params = [Parameter::from(i32::from(mode))];
+            Ok(self.context.expand(cap, &params)?)
+        } else if mode == 0 {
+            Ok(self.context.expand(cap, &[])?)
+        } else {
+            Err(Error::UnsupportedMode(mode))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const INITC: &[u8] = b"\x1b]4;%p1%d;rgb:%p2%{255}%*%{1000}%/%2.2X/%p3%{255}%*%{1000}%/%2.2X/%p4%{255}%*%{1000}%/%2.2X\x1b\\";
+
+    fn terminal(strings: &[(&'static str, &'static [u8])]) -> Terminal<'static> {
+        let mut terminfo = Terminfo::new();
+        terminfo.strings.extend(strings.iter().copied());
+        Terminal::new(terminfo)
+    }
+
+    fn terminfo<'t>(terminal: &'t mut Terminal<'static>) -> &'t mut Terminfo<'static> {
+        match &mut terminal.entry {
+            Entry::Borrowed(terminfo) => terminfo,
+            Entry::Owned(_) => unreachable!("test terminals borrow their entry"),
+        }
+    }
+
+    #[test]
+    fn erase_parameterized() {
+        let mut terminal = terminal(&[("el", b"\x1b[%p1%dK"), ("ed", b"\x1b[%p1%dJ")]);
+        assert_eq!(terminal.erase_in_line(0), Ok(b"\x1b[0K".to_vec()));
+        assert_eq!(terminal.erase_in_line(1), Ok(b"\x1b[1K".to_vec()));
+        assert_eq!(terminal.erase_in_display(2), Ok(b"\x1b[2J".to_vec()));
+    }
+
+    #[test]
+    fn erase_not_parameterized() {
+        let mut terminal = terminal(&[("el", b"\x1b[K"), ("ed", b"\x1b[J")]);
+        assert_eq!(terminal.erase_in_line(0), Ok(b"\x1b[K".to_vec()));
+        assert_eq!(terminal.erase_in_display(0), Ok(b"\x1b[J".to_vec()));
+        assert_eq!(terminal.erase_in_line(1), Err(Error::UnsupportedMode(1)));
+        assert_eq!(terminal.erase_in_display(2), Err(Error::UnsupportedMode(2)));
+    }
+
+    #[test]
+    fn erase_padded() {
+        let mut terminal = terminal(&[("el", b"\x1b[K$<3>"), ("ed", b"\x1b[J$<50>")]);
+        assert_eq!(terminal.erase_in_line(0), Ok(b"\x1b[K".to_vec()));
+        assert_eq!(terminal.erase_in_display(0), Ok(b"\x1b[J".to_vec()));
+    }
+
+    #[test]
+    fn erase_literal_percent() {
+        let mut terminal = terminal(&[("el", b"%%p\x1b[K")]);
+        assert_eq!(terminal.erase_in_line(0), Ok(b"%p\x1b[K".to_vec()));
+        assert_eq!(terminal.erase_in_line(1), Err(Error::UnsupportedMode(1)));
+    }
+
+    #[test]
+    fn erase_missing() {
+        let mut terminal = terminal(&[]);
+        assert_eq!(
+            terminal.erase_in_line(0),
+            Err(Error::MissingCapability("el".to_string()))
+        );
+        assert_eq!(
+            terminal.erase_in_display(0),
+            Err(Error::MissingCapability("ed".to_string()))
+        );
+    }
+
+    #[test]
+    fn init_color() {
+        let mut terminal = terminal(&[("initc", INITC)]);
+        terminfo(&mut terminal).booleans.insert("ccc");
+        assert_eq!(
+            terminal.init_color(1, [1000, 0, 500]),
+            Ok(b"\x1b]4;1;rgb:FF/00/7F\x1b\\".to_vec())
+        );
+    }
+
+    #[test]
+    fn init_color_unsupported() {
+        let mut no_ccc = terminal(&[("initc", INITC)]);
+        assert_eq!(
+            no_ccc.init_color(1, [1000, 0, 500]),
+            Err(Error::MissingCapability("ccc".to_string()))
+        );
+        let mut no_initc = terminal(&[]);
+        terminfo(&mut no_initc).booleans.insert("ccc");
+        assert_eq!(
+            no_initc.init_color(1, [1000, 0, 500]),
+            Err(Error::MissingCapability("initc".to_string()))
+        );
+    }
+
+    #[test]
+    fn features() {
+        assert!(terminal(&[]).features().is_empty());
+
+        let mut terminal = terminal(&[
+            ("sitm", b"\x1b[3m"),
+            ("Smulx", b"\x1b[4:%p1%dm"),
+            ("BE", b"\x1b[?2004h"),
+        ]);
+        terminfo(&mut terminal).booleans.insert("Tc");
+        let features = terminal.features();
+        assert!(features.contains(FeatureSet::TRUECOLOR | FeatureSet::ITALICS));
+        assert!(features.contains(FeatureSet::STYLED_UNDERLINE));
+        assert!(features.contains(FeatureSet::BRACKETED_PASTE));
+        assert!(!features.contains(FeatureSet::STRIKETHROUGH));
+        assert!(!features.contains(FeatureSet::MOUSE));
+    }
+
+    #[test]
+    fn set_region_home() {
+        let mut with_home = terminal(&[("csr", b"\x1b[%i%p1%d;%p2%dr"), ("home", b"\x1b[H")]);
+        assert_eq!(
+            with_home.set_region_home(2, 20),
+            Ok(b"\x1b[3;21r\x1b[H".to_vec())
+        );
+
+        let mut with_cup = terminal(&[
+            ("csr", b"\x1b[%i%p1%d;%p2%dr"),
+            ("cup", b"\x1b[%i%p1%d;%p2%dH"),
+        ]);
+        assert_eq!(
+            with_cup.set_region_home(0, 9),
+            Ok(b"\x1b[1;10r\x1b[1;1H".to_vec())
+        );
+    }
+
+    #[test]
+    fn set_region_home_missing() {
+        let mut no_csr = terminal(&[("home", b"\x1b[H")]);
+        assert_eq!(
+            no_csr.set_region_home(0, 9),
+            Err(Error::MissingCapability("csr".to_string()))
+        );
+        let mut no_home = terminal(&[("csr", b"\x1b[%i%p1%d;%p2%dr")]);
+        assert_eq!(
+            no_home.set_region_home(0, 9),
+            Err(Error::MissingCapability("home".to_string()))
+        );
+    }
+
+    #[test]
+    fn sgr() {
+        let mut terminal = terminal(&[
+            (
+                "sgr",
+                b"\x1b[0%?%p1%p6%|%t;1%;%?%p2%t;4%;%?%p1%p3%|%t;7%;%?%p4%t;5%;m%?%p9%t\x0e%e\x0f%;",
+            ),
+            ("sgr0", b"\x1b[m"),
+        ]);
+        assert_eq!(
+            terminal.sgr(SgrAttrs::default()),
+            Ok(b"\x1b[0m\x0f".to_vec())
+        );
+        let attrs = SgrAttrs {
+            underline: true,
+            bold: true,
+            altcharset: true,
+            ..SgrAttrs::default()
+        };
+        assert_eq!(terminal.sgr(attrs), Ok(b"\x1b[0;1;4m\x0e".to_vec()));
+        let attrs = SgrAttrs {
+            reverse: true,
+            ..SgrAttrs::default()
+        };
+        assert_eq!(terminal.sgr(attrs), Ok(b"\x1b[0;7m\x0f".to_vec()));
+    }
+
+    #[test]
+    fn sgr_fallback() {
+        let mut no_sgr0 = terminal(&[("bold", b"\x1b[1m")]);
+        assert_eq!(
+            no_sgr0.sgr(SgrAttrs::default()),
+            Err(Error::MissingCapability("sgr0".to_string()))
+        );
+
+        let mut terminal = terminal(&[
+            ("sgr0", b"\x1b[m"),
+            ("bold", b"\x1b[1m"),
+            ("smul", b"\x1b[4m"),
+        ]);
+        assert_eq!(terminal.sgr(SgrAttrs::default()), Ok(b"\x1b[m".to_vec()));
+        let attrs = SgrAttrs {
+            underline: true,
+            bold: true,
+            ..SgrAttrs::default()
+        };
+        assert_eq!(terminal.sgr(attrs), Ok(b"\x1b[m\x1b[4m\x1b[1m".to_vec()));
+        let attrs = SgrAttrs {
+            blink: true,
+            ..SgrAttrs::default()
+        };
+        assert_eq!(
+            terminal.sgr(attrs),
+            Err(Error::MissingCapability("blink".to_string()))
+        );
+    }
+
+    #[test]
+    fn set_color() {
+        let mut terminal = terminal(&[
+            ("setaf", b"\x1b[3%p1%dm"),
+            ("setab", b"\x1b[4%p1%dm"),
+            ("setf", b"\x1b[1%p1%dm"),
+        ]);
+        terminfo(&mut terminal).numbers.insert("colors", 8);
+        assert_eq!(terminal.set_fg(1), Ok(b"\x1b[31m".to_vec()));
+        assert_eq!(terminal.set_bg(7), Ok(b"\x1b[47m".to_vec()));
+        assert_eq!(terminal.set_fg(8), Err(Error::ColorOutOfRange(8)));
+        assert_eq!(terminal.set_bg(255), Err(Error::ColorOutOfRange(255)));
+    }
+
+    #[test]
+    fn set_color_fallback() {
+        let mut no_colors = terminal(&[]);
+        assert_eq!(
+            no_colors.set_fg(1),
+            Err(Error::MissingCapability("setaf".to_string()))
+        );
+        assert_eq!(
+            no_colors.set_bg(1),
+            Err(Error::MissingCapability("setab".to_string()))
+        );
+
+        let mut terminal = terminal(&[("setf", b"\x1b[1%p1%dm"), ("setb", b"\x1b[2%p1%dm")]);
+        // Red and blue are swapped for `setf` and `setb`.
+        assert_eq!(terminal.set_fg(1), Ok(b"\x1b[14m".to_vec()));
+        assert_eq!(terminal.set_fg(4), Ok(b"\x1b[11m".to_vec()));
+        assert_eq!(terminal.set_bg(2), Ok(b"\x1b[22m".to_vec()));
+        assert_eq!(terminal.set_bg(3), Ok(b"\x1b[26m".to_vec()));
+        assert_eq!(terminal.set_fg(9), Ok(b"\x1b[112m".to_vec()));
+    }
+
+    #[test]
+    fn padding() {
+        let flash = b"\x1b[?5h$<100/>\x1b[?5l";
+        let mut terminfo = Terminfo::new();
+        terminfo
+            .strings
+            .extend([("flash", &flash[..]), ("pad", b"*")]);
+        terminfo.numbers.insert("pb", 1200);
+        terminfo.booleans.insert("xon");
+        let mut terminal = Terminal::new(terminfo);
+
+        assert_eq!(
+            terminal.context.expand(flash, &[]),
+            Ok(b"\x1b[?5h\x1b[?5l".to_vec())
+        );
+
+        terminal.set_baud_rate(Some(300));
+        assert_eq!(
+            terminal.context.expand(flash, &[]),
+            Ok(b"\x1b[?5h\x1b[?5l".to_vec())
+        );
+
+        terminal.set_baud_rate(Some(1200));
+        assert_eq!(
+            terminal.context.expand(flash, &[]),
+            Ok(b"\x1b[?5h*************\x1b[?5l".to_vec())
+        );
+        assert_eq!(terminal.context.expand(b"$<100>", &[]), Ok(vec![]));
+    }
+
+    #[test]
+    fn entry_accessors() {
+        let terminfo = || {
+            let mut terminfo = Terminfo::new();
+            terminfo.booleans.insert("am");
+            terminfo.numbers.insert("cols", 80);
+            terminfo.strings.insert("bel", b"\x07");
+            terminfo
+        };
+        let owned = Terminal::with_entry(Entry::Owned(terminfo().into_owned()));
+        for terminal in [Terminal::new(terminfo()), owned] {
+            assert!(terminal.get_bool("am"));
+            assert!(!terminal.get_bool("bw"));
+            assert_eq!(terminal.get_number("cols"), Some(80));
+            assert_eq!(terminal.get_number("lines"), None);
+            assert_eq!(terminal.get_string("bel"), Some(&b"\x07"[..]));
+            assert_eq!(terminal.get_string("cup"), None);
+        }
+    }
+
+    #[test]
+    fn cap_by_name() {
+        let mut terminal = terminal(&[("cup", b"\x1b[%i%p1%d;%p2%dH")]);
+        let params = [4, 9].map(Parameter::from);
+        assert_eq!(terminal.cap("cup", &params).unwrap(), b"\x1b[5;10H");
+        assert!(matches!(
+            terminal.cap("home", &[]),
+            Err(crate::Error::Terminal(Error::MissingCapability(name))) if name == "home"
+        ));
+    }
+}
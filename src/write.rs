@@ -0,0 +1,398 @@
+// Copyright 2025 Pavel Roskin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Writing terminfo database files
+
+use std::collections::BTreeMap;
+
+use crate::parse::{
+    BOOL_NAMES, CANCELED_BOOLEAN, CANCELED_ENTRY, NUMBER_NAMES, STRING_NAMES, Terminfo,
+    TerminfoMagic,
+};
+
+const ABSENT_OFFSET: u16 = 0xffff;
+const CANCELED_OFFSET: u16 = 0xfffe;
+
+/// Errors reported when writing a terminfo database
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+    /// A number cannot be represented in the selected format
+    #[error("Number out of range for {0}")]
+    NumberOutOfRange(String),
+    /// The string table is too large for 16-bit offsets
+    #[error("String table too large")]
+    StringTableOverflow,
+    /// The terminal names don't fit into the header
+    #[error("Terminal names too long")]
+    NamesTooLong,
+}
+
+/// Number of leading elements to write for the predefined capabilities
+fn base_count(names: &[&str], is_present: impl Fn(&str) -> bool) -> usize {
+    names
+        .iter()
+        .rposition(|name| is_present(name))
+        .map_or(0, |index| index + 1)
+}
+
+fn write_le16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_number(buffer: &mut Vec<u8>, value: i32, number_size: usize) {
+    if number_size == 4 {
+        buffer.extend_from_slice(&value.to_le_bytes());
+    } else {
+        // The caller has checked that the value fits.
+        buffer.extend_from_slice(&(value as i16).to_le_bytes());
+    }
+}
+
+fn write_count(buffer: &mut Vec<u8>, count: usize) -> Result<(), Error> {
+    match u16::try_from(count) {
+        Ok(count) => write_le16(buffer, count),
+        Err(_) => return Err(Error::StringTableOverflow),
+    }
+    Ok(())
+}
+
+fn align_buffer(buffer: &mut Vec<u8>) {
+    if !buffer.len().is_multiple_of(2) {
+        buffer.push(0);
+    }
+}
+
+/// String capability to be written
+#[derive(Clone, Copy)]
+enum StringValue<'a> {
+    Present(&'a [u8]),
+    Canceled,
+}
+
+/// Table of NUL terminated strings addressed by 16-bit offsets
+#[derive(Default)]
+struct StringTable {
+    data: Vec<u8>,
+    count: usize,
+}
+
+impl StringTable {
+    /// Add a string to the table and return its offset
+    fn push(&mut self, value: &[u8]) -> Result<u16, Error> {
+        let offset = match u16::try_from(self.data.len()) {
+            Ok(offset) if offset < ABSENT_OFFSET - 1 => offset,
+            _ => return Err(Error::StringTableOverflow),
+        };
+        self.data.extend_from_slice(value);
+        self.data.push(0);
+        self.count += 1;
+        Ok(offset)
+    }
+}
+
+impl Terminfo<'_> {
+    /// Serialize the predefined capabilities in the original 16-bit format
+    ///
+    /// Extended capabilities are dropped, as the original format has no place
+    /// for them. This produces the most compatible, if lossy, database.
+    ///
+    /// Returns an error if a number doesn't fit into 16 bits.
+    pub fn to_base_bytes_16(&self) -> Result<Vec<u8>, Error> {
+        for name in NUMBER_NAMES {
+            if let Some(&number) = self.numbers.get(name)
+                && i16::try_from(number).is_err()
+            {
+                return Err(Error::NumberOutOfRange(name.to_string()));
+            }
+        }
+        let mut buffer = vec![];
+        self.write_base(&mut buffer, 2)?;
+        Ok(buffer)
+    }
+
+    /// Serialize the entry in the compiled terminfo format
+    ///
+    /// The 32-bit number format is used if any number doesn't fit into 16
+    /// bits, the original 16-bit format is used otherwise. Capabilities that
+    /// are not predefined are written to the extended section.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let number_size = if self
+            .numbers
+            .values()
+            .all(|&number| i16::try_from(number).is_ok())
+        {
+            2
+        } else {
+            4
+        };
+        let mut buffer = vec![];
+        self.write_base(&mut buffer, number_size)?;
+        self.write_extended(&mut buffer, number_size)?;
+        Ok(buffer)
+    }
+
+    /// Value of a boolean capability as written to the database
+    fn bool_value(&self, name: &str) -> u8 {
+        if self.booleans.contains(name) {
+            1
+        } else if self.canceled_booleans.contains(name) {
+            CANCELED_BOOLEAN
+        } else {
+            0
+        }
+    }
+
+    /// Value of a numeric capability as written to the database
+    fn number_value(&self, name: &str) -> Option<i32> {
+        self.numbers.get(name).copied().or_else(|| {
+            self.canceled_numbers
+                .contains(name)
+                .then_some(CANCELED_ENTRY)
+        })
+    }
+
+    /// Value of a string capability as written to the database
+    fn string_value(&self, name: &str) -> Option<StringValue<'_>> {
+        self.strings
+            .get(name)
+            .map(|value| StringValue::Present(value))
+            .or_else(|| {
+                self.canceled_strings
+                    .contains(name)
+                    .then_some(StringValue::Canceled)
+            })
+    }
+
+    fn write_base(&self, buffer: &mut Vec<u8>, number_size: usize) -> Result<(), Error> {
+        let bool_count = base_count(&BOOL_NAMES, |name| self.bool_value(name) != 0);
+        let num_count = base_count(&NUMBER_NAMES, |name| self.number_value(name).is_some());
+        let str_count = base_count(&STRING_NAMES, |name| self.string_value(name).is_some());
+
+        let mut str_offsets = Vec::with_capacity(str_count);
+        let mut str_table = StringTable::default();
+        for name in STRING_NAMES.iter().take(str_count) {
+            match self.string_value(name) {
+                Some(StringValue::Present(value)) => str_offsets.push(str_table.push(value)?),
+                Some(StringValue::Canceled) => str_offsets.push(CANCELED_OFFSET),
+                None => str_offsets.push(ABSENT_OFFSET),
+            }
+        }
+
+        let mut term_names = self.term_names.as_bytes().to_vec();
+        term_names.push(0);
+        let Ok(term_names_size) = u16::try_from(term_names.len()) else {
+            return Err(Error::NamesTooLong);
+        };
+
+        let magic = if number_size == 4 {
+            TerminfoMagic::Magic2
+        } else {
+            TerminfoMagic::Magic1
+        };
+        write_le16(buffer, magic as u16);
+        write_le16(buffer, term_names_size);
+        write_le16(buffer, bool_count as u16);
+        write_le16(buffer, num_count as u16);
+        write_le16(buffer, str_count as u16);
+        write_count(buffer, str_table.data.len())?;
+        buffer.extend_from_slice(&term_names);
+        for name in BOOL_NAMES.iter().take(bool_count) {
+            buffer.push(self.bool_value(name));
+        }
+        align_buffer(buffer);
+        for name in NUMBER_NAMES.iter().take(num_count) {
+            write_number(buffer, self.number_value(name).unwrap_or(-1), number_size);
+        }
+        for offset in str_offsets {
+            write_le16(buffer, offset);
+        }
+        buffer.extend_from_slice(&str_table.data);
+
+        Ok(())
+    }
+
+    fn write_extended(&self, buffer: &mut Vec<u8>, number_size: usize) -> Result<(), Error> {
+        let booleans: BTreeMap<_, _> = self
+            .booleans
+            .iter()
+            .chain(&self.canceled_booleans)
+            .filter(|name| !BOOL_NAMES.contains(name))
+            .map(|name| (*name, self.bool_value(name)))
+            .collect();
+        let numbers: BTreeMap<_, _> = self
+            .numbers
+            .keys()
+            .chain(&self.canceled_numbers)
+            .filter(|name| !NUMBER_NAMES.contains(name))
+            .filter_map(|name| Some((*name, self.number_value(name)?)))
+            .collect();
+        let strings: BTreeMap<_, _> = self
+            .strings
+            .keys()
+            .chain(&self.canceled_strings)
+            .filter(|name| !STRING_NAMES.contains(name))
+            .filter_map(|name| Some((*name, self.string_value(name)?)))
+            .collect();
+        if booleans.is_empty() && numbers.is_empty() && strings.is_empty() {
+            return Ok(());
+        }
+
+        // String values come first in the table, followed by the names.
+        let mut str_table = StringTable::default();
+        let mut str_offsets = Vec::with_capacity(strings.len());
+        for value in strings.values() {
+            match value {
+                StringValue::Present(value) => str_offsets.push(str_table.push(value)?),
+                StringValue::Canceled => str_offsets.push(CANCELED_OFFSET),
+            }
+        }
+        let mut names_table = StringTable::default();
+        let mut name_offsets = vec![];
+        let names = booleans.keys().chain(numbers.keys()).chain(strings.keys());
+        for name in names {
+            name_offsets.push(names_table.push(name.as_bytes())?);
+        }
+        str_table.data.extend_from_slice(&names_table.data);
+        str_table.count += names_table.count;
+        if str_table.data.len() > usize::from(ABSENT_OFFSET - 1) {
+            return Err(Error::StringTableOverflow);
+        }
+
+        align_buffer(buffer);
+        write_count(buffer, booleans.len())?;
+        write_count(buffer, numbers.len())?;
+        write_count(buffer, strings.len())?;
+        write_count(buffer, str_table.count)?;
+        write_count(buffer, str_table.data.len())?;
+        buffer.extend(booleans.values());
+        align_buffer(buffer);
+        for &number in numbers.values() {
+            write_number(buffer, number, number_size);
+        }
+        for offset in str_offsets {
+            write_le16(buffer, offset);
+        }
+        for offset in name_offsets {
+            write_le16(buffer, offset);
+        }
+        buffer.extend_from_slice(&str_table.data);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use collection_literals::collection;
+
+    use super::*;
+    use crate::parse::parse;
+
+    fn sample_terminfo() -> Terminfo<'static> {
+        let mut terminfo = Terminfo::new();
+        terminfo.booleans = collection!("am", "xenl", "Ext");
+        terminfo.numbers = collection!("cols" => 80, "lines" => 24, "Extnum" => 1);
+        terminfo.strings = collection!(
+            "bel" => b"\x07".as_slice(),
+            "cup" => b"\x1b[%i%p1%d;%p2%dH",
+            "Smulx" => b"\x1b[4:%p1%dm",
+        );
+        terminfo
+    }
+
+    #[test]
+    fn base_16_round_trip() {
+        let terminfo = sample_terminfo();
+        let buffer = terminfo.to_base_bytes_16().unwrap();
+        let parsed = parse(&buffer).unwrap();
+        assert_eq!(parsed.booleans, collection!("am", "xenl"));
+        assert_eq!(parsed.numbers, collection!("cols" => 80, "lines" => 24));
+        assert_eq!(
+            parsed.strings,
+            collection!(
+                "bel" => b"\x07".as_slice(),
+                "cup" => b"\x1b[%i%p1%d;%p2%dH",
+            )
+        );
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let mut terminfo = sample_terminfo();
+        terminfo.term_names = "myterm|mt|My terminal";
+        for buffer in [terminfo.to_bytes(), terminfo.to_base_bytes_16()] {
+            let buffer = buffer.unwrap();
+            let parsed = parse(&buffer).unwrap();
+            assert_eq!(parsed.names().collect::<Vec<_>>(), vec!["myterm", "mt"]);
+            assert_eq!(parsed.description(), Some("My terminal"));
+        }
+    }
+
+    #[test]
+    fn base_16_empty() {
+        let terminfo = Terminfo::new();
+        let buffer = terminfo.to_base_bytes_16().unwrap();
+        let parsed = parse(&buffer).unwrap();
+        assert!(parsed.booleans.is_empty());
+        assert!(parsed.numbers.is_empty());
+        assert!(parsed.strings.is_empty());
+    }
+
+    #[test]
+    fn full_round_trip() {
+        let mut terminfo = sample_terminfo();
+        terminfo.canceled_booleans = collection!("bw", "Extbool");
+        terminfo.canceled_numbers = collection!("it", "Extcanceled");
+        terminfo.canceled_strings = collection!("bold", "Extstr");
+        let buffer = terminfo.to_bytes().unwrap();
+        assert_eq!(buffer[..2], (TerminfoMagic::Magic1 as u16).to_le_bytes());
+        let parsed = parse(&buffer).unwrap();
+        assert_eq!(parsed.booleans, terminfo.booleans);
+        assert_eq!(parsed.numbers, terminfo.numbers);
+        assert_eq!(parsed.strings, terminfo.strings);
+        assert_eq!(parsed.canceled_booleans, terminfo.canceled_booleans);
+        assert_eq!(parsed.canceled_numbers, terminfo.canceled_numbers);
+        assert_eq!(parsed.canceled_strings, terminfo.canceled_strings);
+    }
+
+    #[test]
+    fn full_round_trip_32() {
+        let mut terminfo = sample_terminfo();
+        terminfo.numbers.insert("colors", 0x100_0000);
+        terminfo.numbers.insert("Extlarge", 0x10000);
+        let buffer = terminfo.to_bytes().unwrap();
+        assert_eq!(buffer[..2], (TerminfoMagic::Magic2 as u16).to_le_bytes());
+        let parsed = parse(&buffer).unwrap();
+        assert_eq!(parsed.booleans, terminfo.booleans);
+        assert_eq!(parsed.numbers, terminfo.numbers);
+        assert_eq!(parsed.strings, terminfo.strings);
+    }
+
+    #[test]
+    fn full_round_trip_base_only() {
+        let mut terminfo = Terminfo::new();
+        terminfo.booleans.insert("bw");
+        terminfo.strings.insert("bel", b"\x07");
+        let buffer = terminfo.to_bytes().unwrap();
+        assert_eq!(buffer, terminfo.to_base_bytes_16().unwrap());
+        let parsed = parse(&buffer).unwrap();
+        assert_eq!(parsed.booleans, terminfo.booleans);
+        assert_eq!(parsed.strings, terminfo.strings);
+    }
+
+    #[test]
+    fn base_16_number_out_of_range() {
+        let mut terminfo = sample_terminfo();
+        terminfo.numbers.insert("colors", 0x10000);
+        assert_eq!(
+            terminfo.to_base_bytes_16(),
+            Err(Error::NumberOutOfRange("colors".to_string()))
+        );
+    }
+}
@@ -4,7 +4,7 @@
 use std::fs;
 
 use terminfo_lean::{
-    locate::{locate, search_directories},
+    locate::{iter_all_terminals, locate},
     parse::parse,
 };
 
@@ -12,38 +12,25 @@ use terminfo_lean::{
 // Print statements are useful to debug failures.
 #[test]
 fn test_all_terminals() {
-    let dirs = search_directories();
-    for dir in dirs {
-        let Ok(dir) = fs::read_dir(&dir) else {
-            continue;
-        };
-        for leaf in dir {
-            let leaf = leaf.unwrap().path();
-            let Ok(leaf) = fs::read_dir(&leaf) else {
-                continue;
-            };
-            for term in leaf {
-                let term_name = term.unwrap().file_name();
-                println!("terminal: {term_name:?}");
-                let terminfo_path = locate(&term_name).unwrap();
-                let terminfo_buffer = fs::read(terminfo_path).unwrap();
-                let terminfo = match parse(&terminfo_buffer) {
-                    Ok(terminfo) => terminfo,
-                    Err(err) => {
-                        println!("Parse error: {err}, terminfo_buffer: {terminfo_buffer:?}");
-                        panic!("Cannot parse terminfo for terminal {term_name:?}");
-                    }
-                };
-                for key in terminfo.booleans {
-                    println!("\t{key},");
-                }
-                for (key, value) in terminfo.numbers {
-                    println!("\t{key}#{value},");
-                }
-                for (key, value) in terminfo.strings {
-                    println!("\t{key}={:?},", String::from_utf8_lossy(value));
-                }
+    for (term_name, terminfo_path) in iter_all_terminals() {
+        println!("terminal: {term_name:?}");
+        assert!(locate(&term_name).is_ok());
+        let terminfo_buffer = fs::read(terminfo_path).unwrap();
+        let terminfo = match parse(&terminfo_buffer) {
+            Ok(terminfo) => terminfo,
+            Err(err) => {
+                println!("Parse error: {err}, terminfo_buffer: {terminfo_buffer:?}");
+                panic!("Cannot parse terminfo for terminal {term_name:?}");
             }
+        };
+        for key in terminfo.booleans {
+            println!("\t{key},");
+        }
+        for (key, value) in terminfo.numbers {
+            println!("\t{key}#{value},");
+        }
+        for (key, value) in terminfo.strings {
+            println!("\t{key}={:?},", String::from_utf8_lossy(value));
         }
     }
 }
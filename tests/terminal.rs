@@ -0,0 +1,17 @@
+// Don't count this test towards test coverage, as it's system specific.
+#![cfg(not(coverage))]
+
+use terminfo_lean::{Error, expand::Parameter, terminal::Terminal};
+
+// Expand `cup` for xterm found in the system terminfo database.
+#[test]
+fn test_xterm_cup() -> Result<(), Error> {
+    temp_env::with_vars([("TERM", Some("xterm")), ("TERMINFO", None)], || {
+        let mut terminal = Terminal::from_env()?;
+        let params = [4, 9].map(Parameter::from);
+        assert_eq!(terminal.cap("cup", &params)?, b"\x1b[5;10H");
+        assert_eq!(terminal.set_fg(1)?, b"\x1b[31m");
+        assert!(terminal.cap("Nonexistent", &[]).is_err());
+        Ok(())
+    })
+}